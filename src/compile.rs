@@ -0,0 +1,141 @@
+use std::{cell::OnceCell, collections::HashMap, rc::Rc};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    module::{Instr, Module},
+    validate::validate_body,
+};
+
+/// A module whose function bodies have been decoded and checked once, ready
+/// to be instantiated any number of times. Cloning it is cheap.
+#[derive(Clone, Debug)]
+pub struct CompiledModule {
+    pub(crate) module: Rc<Module>,
+    // Maximum operand stack height reached by each function body. Bodies that
+    // were left to be decoded lazily are checked and measured the first time
+    // the function is looked up.
+    pub(crate) max_stack_heights: Rc<[OnceCell<usize>]>,
+    // Where the blocks of each function body end, found the first time the
+    // function is looked up.
+    pub(crate) block_targets: Rc<[OnceCell<Rc<BlockTargets>>]>,
+}
+
+impl CompiledModule {
+    // Unless `decode_all` is set, bodies that are still undecoded (see
+    // `Config::lazy_function_bodies`) are left alone.
+    pub(crate) fn new(module: Module, decode_all: bool) -> Result<Self> {
+        module.validate_exports()?;
+        let mut max_stack_heights = Vec::with_capacity(module.funcs.len());
+        for (idx, func) in module.funcs.iter().enumerate() {
+            if !decode_all && !func.is_decoded() {
+                max_stack_heights.push(OnceCell::new());
+                continue;
+            }
+
+            let height = func
+                .body()
+                .and_then(|body| validate_body(&module, func, body))
+                .with_context(|| {
+                    let idx = idx as u32 + module.num_imported_funcs();
+                    format!("compile {}", module.describe_func(idx))
+                })?;
+            max_stack_heights.push(OnceCell::from(height));
+        }
+
+        Ok(Self {
+            block_targets: module.funcs.iter().map(|_| OnceCell::new()).collect(),
+            module: Rc::new(module),
+            max_stack_heights: max_stack_heights.into(),
+        })
+    }
+}
+
+impl Module {
+    /// Decodes and checks every function body once, the result can be
+    /// instantiated many times with [`crate::instance::Instance::from_compiled`].
+    pub fn compile(&self) -> Result<CompiledModule> {
+        CompiledModule::new(self.clone(), true)
+    }
+}
+
+// Where execution continues past the blocks of a function body, keyed by the
+// offset of the `block`, `loop` or `if` starting them.
+#[derive(Debug, Default)]
+pub(crate) struct BlockTargets {
+    // Offset of the `end` closing each block.
+    pub(crate) ends: HashMap<usize, usize>,
+    // Offset of the `else` of every `if` that has one.
+    pub(crate) elses: HashMap<usize, usize>,
+}
+
+pub(crate) fn block_targets(body: &[Instr]) -> Result<BlockTargets> {
+    let mut targets = BlockTargets::default();
+    let mut open = Vec::new();
+
+    for (pc, instr) in body.iter().enumerate() {
+        match instr {
+            Instr::Block(_) | Instr::Loop(_) | Instr::If(_) => open.push(pc),
+            Instr::Else => match open.last() {
+                Some(start) if matches!(body[*start], Instr::If(_)) => {
+                    targets.elses.insert(*start, pc);
+                }
+                _ => bail!("else at {pc} isn't in an if"),
+            },
+            Instr::End => {
+                if let Some(start) = open.pop() {
+                    targets.ends.insert(start, pc);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(start) = open.pop() {
+        bail!("block at {start} isn't closed by an end");
+    }
+
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        imports::Imports, instance::Instance, store::Store, test_util::*,
+        validate::VALIDATED_BODIES,
+    };
+
+    #[test]
+    fn compile_once() {
+        let bytes = single_func(&[], &[I32], &[], vec![0x41, 1, 0x0b]);
+        let mut store = Store::default();
+        let module = Module::from_bytes(&store, &bytes).unwrap();
+
+        let before = VALIDATED_BODIES.with(|count| count.get());
+        let compiled = module.compile().unwrap();
+        for _ in 0..3 {
+            let instance = Instance::from_compiled(&mut store, &compiled, &Imports::new()).unwrap();
+            let f = instance.exports.get_function("f").unwrap();
+            f.call(&mut store, &[]).unwrap();
+        }
+        assert_eq!(VALIDATED_BODIES.with(|count| count.get()) - before, 1);
+    }
+
+    #[test]
+    fn errors_name_the_function_index() {
+        // Function 1, after the imported one, pops from an empty stack
+        let bytes = module(vec![
+            section(1, vec(vec![func_type(&[], &[I32])])),
+            section(2, vec(vec![import("env", "g", vec![FUNC, 0])])),
+            section(3, vec(vec![leb(0)])),
+            section(10, vec(vec![body(&[], vec![0x6a, 0x0b])])),
+        ]);
+        let module = Module::from_bytes(&Store::default(), &bytes).unwrap();
+        let e = module.compile().unwrap_err();
+        assert!(
+            format!("{e:#}").starts_with("compile function 1: "),
+            "{e:#}"
+        );
+    }
+}
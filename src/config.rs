@@ -0,0 +1,58 @@
+pub const DEFAULT_DEADLINE_CHECK_INTERVAL: u32 = 1000;
+
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Defer decoding function bodies until the function is first looked up.
+    /// Speeds up loading large modules, but malformed bodies are only
+    /// reported when the function is used.
+    pub lazy_function_bodies: bool,
+
+    /// Maximum number of functions a module may declare, unlimited if
+    /// `None`.
+    pub max_functions: Option<u64>,
+
+    /// Maximum number of exports a module may declare, unlimited if `None`.
+    pub max_exports: Option<u64>,
+
+    /// Maximum number of values on a call's operand stack, exceeding it traps
+    /// with a stack overflow. Unlimited if `None`.
+    pub max_value_stack: Option<usize>,
+
+    /// Maximum number of values a call may hold in its operand stack and
+    /// locals combined. Checked against the maximum stack height of the
+    /// function before it runs, and while it runs. Unlimited if `None`.
+    pub max_call_memory_slots: Option<usize>,
+
+    /// Number of instructions executed between two checks of the
+    /// [`Store::set_deadline`](crate::store::Store::set_deadline) deadline,
+    /// reading the clock is too slow to do it every time. Defaults to
+    /// `DEFAULT_DEADLINE_CHECK_INTERVAL` if `None`.
+    pub deadline_check_interval: Option<u32>,
+
+    /// Number of pages memories that don't declare a maximum size can grow
+    /// to. Defaults to the 65536 pages a 32-bit address space holds if
+    /// `None`.
+    pub max_memory_pages: Option<u32>,
+
+    /// Reserve room for the maximum size of memories declaring one at
+    /// instantiation, so that `memory.grow` never reallocates. Only the
+    /// minimum size is usable until the memory is grown.
+    pub preallocate_max_memory: bool,
+
+    /// Check that the values stored by `local.set`, `local.tee` and
+    /// `global.set` match the type of the local or global. Meant for
+    /// debugging code generators, validated modules never fail this check.
+    pub check_slot_types: bool,
+
+    /// Trap on memory accesses whose address isn't aligned to the alignment
+    /// the instruction declares. The alignment is only a hint in wasm, this
+    /// is meant to catch code generators declaring the wrong one.
+    pub strict_alignment: bool,
+
+    /// Instantiate modules importing functions that aren't defined, calling
+    /// one of them traps with [`TrapKind::UndefinedImport`] instead.
+    /// Imports defined with the wrong signature still fail instantiation.
+    ///
+    /// [`TrapKind::UndefinedImport`]: crate::error::TrapKind::UndefinedImport
+    pub lenient_linking: bool,
+}
@@ -0,0 +1,41 @@
+//! Snapshots of the execution state at a trap, attached to the error when
+//! core dumps are enabled on a [`Store`](crate::store::Store).
+
+use std::fmt::Display;
+
+use crate::instance::Value;
+
+/// The state of the instance and of every frame on the call stack when a trap
+/// occurred, found in [`Trap::coredump`](crate::error::Trap::coredump).
+#[derive(Clone, Debug, Default)]
+pub struct CoreDump {
+    /// Values of the instance's globals, by global index.
+    pub globals: Vec<Value>,
+    /// Contents of the instance's memories, by memory index.
+    pub memories: Vec<Vec<u8>>,
+    /// The call stack, the frame that trapped first.
+    pub frames: Vec<Frame>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub func: u32,
+    /// Index in the function body of the instruction that was executing.
+    pub offset: usize,
+    pub locals: Vec<Value>,
+    pub stack: Vec<Value>,
+}
+
+impl Display for CoreDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "trap with {} frames on the call stack",
+            self.frames.len()
+        )?;
+        if let Some(frame) = self.frames.first() {
+            write!(f, ", in function {} at {}", frame.func, frame.offset)?;
+        }
+        Ok(())
+    }
+}
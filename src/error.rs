@@ -0,0 +1,126 @@
+//! The error returned by calls into wasm, telling traps apart from invalid
+//! modules and failing host functions.
+
+use std::fmt::Display;
+
+use anyhow::anyhow;
+
+use crate::coredump::CoreDump;
+
+/// Why execution trapped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TrapKind {
+    /// An integer division or remainder by zero.
+    DivByZero,
+    /// A signed division whose result doesn't fit, `i32::MIN / -1`.
+    IntegerOverflow,
+    /// A memory access past the end of the memory.
+    OutOfBounds,
+    /// A memory access whose address isn't aligned as the instruction
+    /// declares. Only checked with
+    /// [`Config::strict_alignment`](crate::config::Config::strict_alignment).
+    Misaligned,
+    /// An `unreachable` instruction ran.
+    Unreachable,
+    /// Too many nested calls, or a call using more stack than allowed by the
+    /// [`Config`](crate::config::Config).
+    StackExhausted,
+    /// The deadline set with
+    /// [`Store::set_deadline`](crate::store::Store::set_deadline) passed.
+    Timeout,
+    /// A host function panicked.
+    HostPanic,
+    /// An imported function that wasn't defined was called, the module was
+    /// instantiated with
+    /// [`Config::lenient_linking`](crate::config::Config::lenient_linking).
+    UndefinedImport { module: String, name: String },
+}
+
+impl TrapKind {
+    // A trap of this kind, with details after the kind's description.
+    pub(crate) fn with_detail(self, detail: impl Display) -> anyhow::Error {
+        anyhow!("{detail}").context(self)
+    }
+}
+
+impl Display for TrapKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let TrapKind::UndefinedImport { module, name } = self {
+            return write!(f, "undefined import {module}.{name}");
+        }
+        let message = match self {
+            TrapKind::DivByZero => "integer divide by zero",
+            TrapKind::IntegerOverflow => "integer overflow",
+            TrapKind::OutOfBounds => "out of bounds memory access",
+            TrapKind::Misaligned => "misaligned memory access",
+            TrapKind::Unreachable => "unreachable executed",
+            TrapKind::StackExhausted => "stack exhausted",
+            TrapKind::Timeout => "timeout",
+            TrapKind::HostPanic => "host function panicked",
+            TrapKind::UndefinedImport { .. } => unreachable!(),
+        };
+        write!(f, "{message}")
+    }
+}
+
+impl std::error::Error for TrapKind {}
+
+#[derive(Debug)]
+pub struct Trap {
+    pub kind: TrapKind,
+    /// Describes the trap and where it happened.
+    pub message: String,
+    /// Only recorded when core dumps are enabled with
+    /// [`Store::enable_coredumps`](crate::store::Store::enable_coredumps).
+    pub coredump: Option<Box<CoreDump>>,
+}
+
+#[derive(Debug)]
+pub enum WasmError {
+    /// A function body is malformed, found when it is decoded lazily.
+    Parse(String),
+    /// The module is invalid, or the call is: the wrong arguments were passed.
+    Validation(String),
+    Trap(Trap),
+    /// A host function returned an error.
+    Host(anyhow::Error),
+}
+
+impl WasmError {
+    // Classifies an error of the interpreter, where traps have a `TrapKind`
+    // in their chain and the other kinds of errors are a `WasmError`.
+    // Anything else is an invalid module or call the interpreter ran into.
+    pub(crate) fn from_error(error: anyhow::Error) -> Self {
+        if let Some(kind) = error.downcast_ref::<TrapKind>() {
+            return WasmError::Trap(Trap {
+                kind: kind.clone(),
+                message: format!("{error:#}"),
+                coredump: error.downcast_ref::<CoreDump>().cloned().map(Box::new),
+            });
+        }
+
+        match error.downcast::<WasmError>() {
+            Ok(error) => error,
+            Err(error) => WasmError::Validation(format!("{error:#}")),
+        }
+    }
+}
+
+impl Display for WasmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WasmError::Parse(message) | WasmError::Validation(message) => write!(f, "{message}"),
+            WasmError::Trap(trap) => write!(f, "{}", trap.message),
+            WasmError::Host(_) => write!(f, "host function failed"),
+        }
+    }
+}
+
+impl std::error::Error for WasmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WasmError::Host(error) => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! Host-provided values for a module's imports.
+
+use std::{collections::HashMap, rc::Rc};
+
+use anyhow::Result;
+
+use crate::{
+    error::TrapKind,
+    instance::Value,
+    module::{FuncType, Val},
+};
+
+/// A function implemented by the host. It gets the arguments in declaration
+/// order and returns the results in the same way. Returning a [`TrapKind`]
+/// as the error traps, other errors fail the call with
+/// [`WasmError::Host`](crate::error::WasmError::Host).
+pub type HostFunc = Rc<dyn Fn(&[Value]) -> Result<Vec<Value>>>;
+
+#[derive(Clone)]
+pub(crate) struct HostFuncDef {
+    pub(crate) ty: FuncType,
+    pub(crate) func: HostFunc,
+    // Whether the function takes whatever params the module imports it
+    // with, only its results are checked.
+    pub(crate) variadic: bool,
+}
+
+impl HostFuncDef {
+    // Stands in for an import that isn't defined when linking leniently.
+    pub(crate) fn undefined(module: &str, name: &str, ty: FuncType) -> Self {
+        let kind = TrapKind::UndefinedImport {
+            module: module.to_string(),
+            name: name.to_string(),
+        };
+        Self {
+            ty,
+            func: Rc::new(move |_| Err(kind.clone().into())),
+            variadic: false,
+        }
+    }
+
+    pub(crate) fn accepts(&self, ty: &FuncType) -> bool {
+        match self.variadic {
+            true => self.ty.results == ty.results,
+            false => self.ty.is_compatible_with(ty),
+        }
+    }
+}
+
+/// The definitions a module's imports are resolved against when it is
+/// instantiated.
+#[derive(Clone, Default)]
+pub struct Imports {
+    funcs: HashMap<(String, String), HostFuncDef>,
+}
+
+impl Imports {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Defines the function imported as `module`.`name`. Instantiation fails
+    /// if the module imports it with a signature other than `ty`.
+    pub fn define(
+        &mut self,
+        module: &str,
+        name: &str,
+        ty: FuncType,
+        func: impl Fn(&[Value]) -> Result<Vec<Value>> + 'static,
+    ) -> &mut Self {
+        let def = HostFuncDef {
+            ty,
+            func: Rc::new(func),
+            variadic: false,
+        };
+        self.funcs
+            .insert((module.to_string(), name.to_string()), def);
+        self
+    }
+
+    /// Defines the function imported as `module`.`name` taking any number of
+    /// arguments: the module can import it with any params, as long as it
+    /// returns `results`. The function gets the arguments of the signature
+    /// it is imported with, it is up to it to make sense of them.
+    pub fn define_variadic(
+        &mut self,
+        module: &str,
+        name: &str,
+        results: Vec<Val>,
+        func: impl Fn(&[Value]) -> Result<Vec<Value>> + 'static,
+    ) -> &mut Self {
+        let def = HostFuncDef {
+            ty: FuncType {
+                params: Vec::new(),
+                results,
+            },
+            func: Rc::new(func),
+            variadic: true,
+        };
+        self.funcs
+            .insert((module.to_string(), name.to_string()), def);
+        self
+    }
+
+    pub(crate) fn func(&self, module: &str, name: &str) -> Option<&HostFuncDef> {
+        self.funcs.get(&(module.to_string(), name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::WasmError, instance::Instance, module::Module, store::Store, test_util::*};
+
+    #[test]
+    fn variadic() {
+        // sum_all is imported with two and with three params
+        let bytes = module(vec![
+            section(
+                1,
+                vec(vec![
+                    func_type(&[I32, I32], &[I32]),
+                    func_type(&[I32, I32, I32], &[I32]),
+                ]),
+            ),
+            section(
+                2,
+                vec(vec![
+                    import("env", "sum_all", vec![FUNC, 0]),
+                    import("env", "sum_all", vec![FUNC, 1]),
+                ]),
+            ),
+            section(3, vec(vec![leb(0), leb(1)])),
+            section(
+                7,
+                vec(vec![export("two", FUNC, 2), export("three", FUNC, 3)]),
+            ),
+            section(
+                10,
+                vec(vec![
+                    body(&[], vec![0x20, 0, 0x20, 1, 0x10, 0, 0x0b]),
+                    body(&[], vec![0x20, 0, 0x20, 1, 0x20, 2, 0x10, 1, 0x0b]),
+                ]),
+            ),
+        ]);
+        let mut imports = Imports::new();
+        imports.define_variadic("env", "sum_all", vec![Val::I32], |args| {
+            let sum = args
+                .iter()
+                .map(|arg| match arg {
+                    Value::I32(n) => *n,
+                    other => panic!("expected an i32, got {other:?}"),
+                })
+                .sum();
+            Ok(vec![Value::I32(sum)])
+        });
+
+        let mut store = Store::default();
+        let exports = instantiate_with(&mut store, &bytes, &imports).exports;
+        let two = exports.get_function("two").unwrap();
+        let three = exports.get_function("three").unwrap();
+        let args = [Value::I32(1), Value::I32(2), Value::I32(3)];
+        assert_eq!(two.call(&mut store, &args[..2]).unwrap(), [Value::I32(3)]);
+        assert_eq!(three.call(&mut store, &args).unwrap(), [Value::I32(6)]);
+    }
+
+    #[test]
+    fn host_functions() {
+        // "f" returns host_add(p0, 10)
+        let bytes = module(vec![
+            section(
+                1,
+                vec(vec![
+                    func_type(&[I32, I32], &[I32]),
+                    func_type(&[I32], &[I32]),
+                ]),
+            ),
+            section(2, vec(vec![import("env", "host_add", vec![FUNC, 0])])),
+            section(3, vec(vec![leb(1)])),
+            section(7, vec(vec![export("f", FUNC, 1)])),
+            section(
+                10,
+                vec(vec![body(&[], vec![0x20, 0, 0x41, 10, 0x10, 0, 0x0b])]),
+            ),
+        ]);
+        let ty = FuncType::new(vec![Val::I32, Val::I32], vec![Val::I32]);
+        let mut imports = Imports::new();
+        imports.define("env", "host_add", ty.clone(), |args| match args {
+            [Value::I32(a), Value::I32(b)] => Ok(vec![Value::I32(a + b)]),
+            _ => unreachable!("the arguments match the signature"),
+        });
+        let mut store = Store::default();
+        let exports = instantiate_with(&mut store, &bytes, &imports).exports;
+        let f = exports.get_function("f").unwrap();
+        assert_eq!(
+            f.call(&mut store, &[Value::I32(5)]).unwrap(),
+            [Value::I32(15)]
+        );
+
+        let module = || Module::from_bytes(&store, &bytes).unwrap();
+        let e = Instance::new(&mut Store::default(), module(), &Imports::new()).err();
+        assert_eq!(e.unwrap().to_string(), "unresolved import env.host_add");
+        let mut wrong_type = Imports::new();
+        wrong_type.define("env", "host_add", FuncType::default(), |_| Ok(vec![]));
+        let e = Instance::new(&mut Store::default(), module(), &wrong_type).err();
+        assert!(e.unwrap().to_string().contains("but is defined with"));
+
+        // Results are checked against the signature
+        let mut wrong_results = Imports::new();
+        wrong_results.define("env", "host_add", ty, |_| Ok(vec![Value::I64(1)]));
+        let exports = instantiate_with(&mut store, &bytes, &wrong_results).exports;
+        let f = exports.get_function("f").unwrap();
+        let e = f.call(&mut store, &[Value::I32(5)]).unwrap_err();
+        match e {
+            WasmError::Validation(message) => {
+                assert!(
+                    message.contains("host function 0 returned [I64(1)]"),
+                    "{message}"
+                )
+            }
+            other => panic!("expected a validation error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn host_panic() {
+        // "f" calls the host function
+        let bytes = module(vec![
+            section(1, vec(vec![func_type(&[], &[])])),
+            section(2, vec(vec![import("env", "boom", vec![FUNC, 0])])),
+            section(3, vec(vec![leb(0)])),
+            section(7, vec(vec![export("f", FUNC, 1)])),
+            section(10, vec(vec![body(&[], vec![0x10, 0, 0x0b])])),
+        ]);
+        let mut imports = Imports::new();
+        imports.define("env", "boom", FuncType::default(), |_| panic!("boom"));
+        let mut store = Store::default();
+        let f = instantiate_with(&mut store, &bytes, &imports)
+            .exports
+            .get_function("f")
+            .unwrap();
+        match f.call(&mut store, &[]).unwrap_err() {
+            WasmError::Trap(trap) => {
+                assert_eq!(trap.kind, TrapKind::HostPanic);
+                assert!(trap.message.contains("boom"), "{}", trap.message);
+            }
+            other => panic!("expected a trap, got {other:?}"),
+        }
+
+        // The store can still be used
+        let f = func(
+            &mut store,
+            &single_func(&[], &[I32], &[], vec![0x41, 1, 0x0b]),
+        );
+        assert_eq!(f.call(&mut store, &[]).unwrap(), [Value::I32(1)]);
+    }
+}
@@ -1,107 +1,3418 @@
-use std::fmt::Display;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::Write,
+    ops::Range,
+    panic::{self, AssertUnwindSafe},
+    rc::Rc,
+};
 
 use crate::{
-    module::{Export, Func, Instr, Module},
+    compile::{block_targets, BlockTargets, CompiledModule},
+    config::Config,
+    coredump::{self, CoreDump},
+    error::{TrapKind, WasmError},
+    imports::{HostFuncDef, Imports},
+    module::{
+        eval_const_expr, BlockType, DataMode, ExportKind, Func, FuncType, GlobalType, ImportKind,
+        Instr, MemArg, MemoryType, Module, Val, MAX_PAGES, PAGE_SIZE,
+    },
     store::Store,
+    validate::validate_body,
 };
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+
+// Deeper call chains trap instead of overflowing the native stack.
+const MAX_CALL_DEPTH: usize = 512;
 
 pub struct Instance {
     pub exports: Exports,
+    ran_start: bool,
+    max_memory_pages: Option<u32>,
 }
+
 impl Instance {
-    pub(crate) fn new(_store: &mut Store, module: Module) -> Result<Self> {
+    pub fn new(store: &mut Store, module: Module, imports: &Imports) -> Result<Self> {
+        Self::from_compiled(store, &CompiledModule::new(module, false)?, imports)
+    }
+
+    /// Instantiates an already compiled module, without decoding or checking
+    /// its functions again.
+    pub fn from_compiled(
+        store: &mut Store,
+        module: &CompiledModule,
+        imports: &Imports,
+    ) -> Result<Self> {
+        let mut host_funcs = Vec::new();
+        for import in &module.module.imports {
+            let ImportKind::Func(ty) = import.kind else {
+                bail!(
+                    "unresolved import {}.{}, only functions can be imported",
+                    import.module,
+                    import.name
+                );
+            };
+            let ty = &module.module.types[ty as usize];
+            let def = match imports.func(&import.module, &import.name) {
+                Some(def) => def.clone(),
+                None if store.config.lenient_linking => {
+                    HostFuncDef::undefined(&import.module, &import.name, ty.clone())
+                }
+                None => bail!("unresolved import {}.{}", import.module, import.name),
+            };
+            if !def.accepts(ty) {
+                bail!(
+                    "import {}.{} has type {ty:?} but is defined with {:?}",
+                    import.module,
+                    import.name,
+                    def.ty
+                );
+            }
+
+            host_funcs.push(def);
+        }
+
+        let mut globals = Vec::new();
+        for (idx, global) in module.module.globals.iter().enumerate() {
+            let value = eval_const_expr(&global.init, |idx| {
+                const_global(&module.module, store, &globals, idx)
+            })
+            .with_context(|| format!("initialize global {idx}"))?;
+            if !global.ty.val.accepts(&value) {
+                bail!(
+                    "global {idx} has type {:?} but is initialized with {value:?}",
+                    global.ty.val
+                );
+            }
+
+            globals.push(store.globals.len());
+            store.globals.push(value);
+        }
+
+        let mut memories = Vec::new();
+        for ty in &module.module.memories {
+            let mut data = match ty.limits.max {
+                Some(max) if store.config.preallocate_max_memory => {
+                    Vec::with_capacity(max as usize * PAGE_SIZE)
+                }
+                _ => Vec::new(),
+            };
+            data.resize(ty.limits.min as usize * PAGE_SIZE, 0);
+
+            memories.push(store.memories.len());
+            store.memories.push(data);
+        }
+
+        for (idx, data) in module.module.data.iter().enumerate() {
+            let DataMode::Active { memory, offset } = &data.mode else {
+                continue;
+            };
+
+            let offset = eval_const_expr(offset, |idx| {
+                const_global(&module.module, store, &globals, idx)
+            })
+            .with_context(|| format!("initialize data segment {idx}"))?;
+            let Value::I32(offset) = offset else {
+                bail!("data segment {idx} has an offset of type {:?}", offset.ty());
+            };
+            let Some(addr) = memories.get(*memory as usize) else {
+                bail!("data segment {idx} refers to unknown memory {memory}");
+            };
+
+            let start = offset as u32 as usize;
+            match store.memories[*addr].get_mut(start..start + data.init.len()) {
+                Some(dst) => dst.copy_from_slice(&data.init),
+                None => bail!("initialize data segment {idx}: out of bounds memory access"),
+            }
+        }
+
+        // Active segments are dropped once copied, only passive ones are left
+        // for `memory.init`
+        let mut datas = Vec::new();
+        for data in &module.module.data {
+            let init = match data.mode {
+                DataMode::Passive => data.init.clone(),
+                DataMode::Active { .. } => Vec::new(),
+            };
+            datas.push(store.datas.len());
+            store.datas.push(init);
+        }
+
+        let exports = Exports {
+            module: module.clone(),
+            globals: globals.into(),
+            memories: memories.into(),
+            datas: datas.into(),
+            host_funcs: host_funcs.into(),
+        };
+
+        let mut ran_start = false;
+        if let Some(start) = module.module.start {
+            match module.module.func_type(start) {
+                Some(ty) if *ty == FuncType::default() => {}
+                Some(ty) => bail!(
+                    "start function {start} has type {ty:?}, expected no params and no results"
+                ),
+                None => bail!("start function {start} doesn't exist"),
+            }
+            exports
+                .call(store, start, &[])
+                .with_context(|| format!("trap in start function {start}"))?;
+            ran_start = true;
+        }
+
         Ok(Self {
-            exports: Exports {
-                exports: module.exports,
-                functions: module.funcs,
-            },
+            exports,
+            ran_start,
+            max_memory_pages: module
+                .module
+                .memories
+                .first()
+                .map(|ty| max_pages(ty, &store.config)),
         })
     }
+
+    /// Whether the module declared a start function, which then ran during
+    /// instantiation.
+    pub fn ran_start(&self) -> bool {
+        self.ran_start
+    }
+
+    /// The number of pages the instance's memory can grow to: its declared
+    /// maximum, or [`Config::max_memory_pages`] if it has none. `None` if the
+    /// instance has no memory.
+    pub fn max_memory_pages(&self) -> Option<u32> {
+        self.max_memory_pages
+    }
+}
+
+// The number of pages a memory of type `ty` can grow to.
+fn max_pages(ty: &MemoryType, config: &Config) -> u32 {
+    ty.limits
+        .max
+        .unwrap_or(config.max_memory_pages.unwrap_or(MAX_PAGES))
+}
+
+// A block the running code is in.
+struct Label {
+    // Where a branch to the label continues: the `end` of a block, the
+    // start of a loop.
+    target: usize,
+    // Height of the operand stack below the block's params.
+    height: usize,
+    // Number of values a branch to the label carries: the results of a
+    // block, the params of a loop.
+    arity: usize,
+}
+
+// Height of the operand stack below the `n` values on top of it.
+fn operands_height(stack: &[Value], n: usize) -> Result<usize> {
+    match stack.len().checked_sub(n) {
+        Some(height) => Ok(height),
+        None => bail!(
+            "block needs {n} operands but the stack only has {}",
+            stack.len()
+        ),
+    }
+}
+
+// Unwinds the operand stack and the labels for a branch to the label
+// `depth` levels out, the labels up to the target's are dropped. Returns
+// where execution continues, `None` when the branch returns from the
+// function.
+fn branch(labels: &mut Vec<Label>, stack: &mut Vec<Value>, depth: u32) -> Result<Option<usize>> {
+    let Some(idx) = labels.len().checked_sub(depth as usize + 1) else {
+        if depth as usize == labels.len() {
+            return Ok(None);
+        }
+        bail!("branch to unknown label {depth}");
+    };
+
+    let label = &labels[idx];
+    // The stack can be below the block when an interceptor popped values
+    let values_start = match stack.len().checked_sub(label.arity) {
+        Some(start) if start >= label.height => start,
+        _ => bail!(
+            "branch needs {} values above the {} of the enclosing blocks but the stack only has {}",
+            label.arity,
+            label.height,
+            stack.len()
+        ),
+    };
+    // Only the values carried by the branch are kept
+    stack.drain(label.height..values_start);
+    let target = label.target;
+    labels.truncate(idx + 1);
+
+    Ok(Some(target))
+}
+
+fn check_value_stack(max: usize, stack_height: usize) -> Result<()> {
+    if stack_height > max {
+        return Err(TrapKind::StackExhausted
+            .with_detail(format!("more than {max} values on the operand stack")));
+    }
+    Ok(())
+}
+
+fn check_call_memory(max: usize, stack_height: usize, num_locals: usize) -> Result<()> {
+    if stack_height + num_locals > max {
+        return Err(TrapKind::StackExhausted.with_detail(format!(
+            "call needs {stack_height} stack slots and {num_locals} locals, more than the limit of {max}"
+        )));
+    }
+    Ok(())
+}
+
+// The value of global `idx` read by a constant expression, `globals` being
+// the addresses of the globals initialized so far. Only immutable globals can
+// be read.
+fn const_global(module: &Module, store: &Store, globals: &[usize], idx: u32) -> Result<Value> {
+    match globals.get(idx as usize) {
+        Some(_) if module.globals[idx as usize].ty.mutable => {
+            bail!("global {idx} is mutable, it can't be used in a constant expression")
+        }
+        Some(addr) => Ok(store.globals[*addr]),
+        None => bail!("unknown global {idx}"),
+    }
+}
+
+// Pops the three i32 operands of a bulk memory instruction, returning them
+// top of the stack first.
+fn pop_i32_triple(stack: &mut Vec<Value>, name: &str) -> Result<(i32, i32, i32)> {
+    match (stack.pop(), stack.pop(), stack.pop()) {
+        (Some(Value::I32(c)), Some(Value::I32(b)), Some(Value::I32(a))) => Ok((c, b, a)),
+        _ => bail!("wrong types for {name}, expected three i32 operands"),
+    }
+}
+
+// The `len` bytes of `bytes` from `start`, both unsigned, trapping when they
+// don't all fit.
+fn checked_range(bytes: &[u8], start: i32, len: i32) -> Result<Range<usize>> {
+    let start = start as u32 as usize;
+    match start.checked_add(len as u32 as usize) {
+        Some(end) if end <= bytes.len() => Ok(start..end),
+        _ => bail!(TrapKind::OutOfBounds),
+    }
+}
+
+fn check_operands(instr: &Instr, stack: &[Value]) -> Result<()> {
+    let expected = instr.operand_types();
+    let Some(operands) = stack.len().checked_sub(expected.len()).map(|n| &stack[n..]) else {
+        bail!("{instr:?} expects operands {expected:?}, got {stack:?}");
+    };
+    if !expected
+        .iter()
+        .zip(operands)
+        .all(|(ty, value)| ty.accepts(value))
+    {
+        bail!("{instr:?} expects operands {expected:?}, got {operands:?}");
+    }
+
+    Ok(())
 }
 
+#[derive(Clone)]
 pub struct Exports {
-    exports: Vec<Export>,
-    functions: Vec<Func>,
+    module: CompiledModule,
+    globals: Rc<[usize]>,
+    // Store addresses of the instance's memories.
+    memories: Rc<[usize]>,
+    // Store addresses of the instance's data segments.
+    datas: Rc<[usize]>,
+    // Implementations of the imported functions, by function index.
+    host_funcs: Rc<[HostFuncDef]>,
 }
 
 impl Exports {
     pub fn get_function(&self, name: &str) -> Result<Function> {
         let mut idx = None;
-        for e in &self.exports {
-            if e.name == name {
+        for e in &self.module.module.exports {
+            if e.kind == ExportKind::Func && e.name == name {
                 idx = Some(e.idx);
                 break;
             }
         }
 
         if let Some(idx) = idx {
-            let func = self.functions.get(idx as usize).unwrap();
-            return Ok(Function {
-                body: func.body.clone(),
-            });
+            return self.function(idx);
         }
 
         bail!("cannot find function {name}");
     }
+
+    /// Iterates over the exported functions. Getting a function can fail
+    /// when its body is decoded lazily and turns out to be malformed.
+    pub fn functions(&self) -> impl Iterator<Item = (&str, Result<Function>)> {
+        self.exports_of(ExportKind::Func)
+            .map(|(name, idx)| (name, self.function(idx)))
+    }
+
+    /// Iterates over the exported globals.
+    pub fn globals(&self) -> impl Iterator<Item = (&str, Global)> {
+        // Exports were checked when the module was compiled
+        self.exports_of(ExportKind::Global).map(|(name, idx)| {
+            let global = Global {
+                addr: self.globals[idx as usize],
+                ty: self.module.module.globals[idx as usize].ty.clone(),
+            };
+            (name, global)
+        })
+    }
+
+    /// Iterates over the exported memories.
+    pub fn memories(&self) -> impl Iterator<Item = (&str, Memory)> {
+        self.exports_of(ExportKind::Memory).map(|(name, idx)| {
+            let memory = Memory {
+                addr: self.memories[idx as usize],
+            };
+            (name, memory)
+        })
+    }
+
+    pub fn get_memory(&self, name: &str) -> Result<Memory> {
+        let idx = self
+            .exports_of(ExportKind::Memory)
+            .find(|(n, _)| *n == name);
+        match idx.and_then(|(_, idx)| self.memories.get(idx as usize)) {
+            Some(addr) => Ok(Memory { addr: *addr }),
+            None => bail!("cannot find memory {name}"),
+        }
+    }
+
+    fn exports_of(&self, kind: ExportKind) -> impl Iterator<Item = (&str, u32)> {
+        self.module
+            .module
+            .exports
+            .iter()
+            .filter(move |e| e.kind == kind)
+            .map(|e| (e.name.as_str(), e.idx))
+    }
+
+    // Calls the function at function index `idx`, be it defined by the
+    // module or by the host.
+    fn call(&self, store: &mut Store, idx: u32, args: &[Value]) -> Result<Vec<Value>> {
+        match self.host_funcs.get(idx as usize) {
+            Some(host) => Self::call_host(host, idx, args),
+            None => self.function(idx)?.call_raw(store, args),
+        }
+    }
+
+    // Kept apart from `call` so that its frame is only on the native stack
+    // when calling into the host.
+    #[inline(never)]
+    fn call_host(host: &HostFuncDef, idx: u32, args: &[Value]) -> Result<Vec<Value>> {
+        // Nothing the interpreter owns is borrowed by the host, so its state
+        // is consistent if the host panics
+        let call = panic::catch_unwind(AssertUnwindSafe(|| (host.func)(args)));
+        let results = match call {
+            Ok(Err(e)) if e.is::<TrapKind>() => return Err(e),
+            Ok(results) => results.map_err(WasmError::Host)?,
+            Err(payload) => {
+                let message = match payload.downcast::<String>() {
+                    Ok(message) => Some(*message),
+                    Err(payload) => payload.downcast_ref::<&str>().map(|s| s.to_string()),
+                };
+                return Err(match message {
+                    Some(message) => TrapKind::HostPanic.with_detail(message),
+                    None => TrapKind::HostPanic.into(),
+                });
+            }
+        };
+        if results.len() != host.ty.results.len()
+            || !host
+                .ty
+                .results
+                .iter()
+                .zip(&results)
+                .all(|(ty, v)| ty.accepts(v))
+        {
+            bail!(
+                "host function {idx} returned {results:?}, expected {:?}",
+                host.ty.results
+            );
+        }
+
+        Ok(results)
+    }
+
+    pub(crate) fn function(&self, idx: u32) -> Result<Function> {
+        let module = &self.module.module;
+        let Some(func) = module.defined_func(idx) else {
+            if (idx as usize) < self.host_funcs.len() {
+                bail!("function {idx} is implemented by the host");
+            }
+            bail!("unknown function {idx}");
+        };
+        let defined = (idx - module.num_imported_funcs()) as usize;
+
+        let body = func
+            .shared_body()
+            .map_err(|e| WasmError::Parse(format!("parse body of function {idx}: {e:#}")))?
+            .clone();
+        // Lazily decoded bodies weren't checked when the module was compiled
+        let max_stack_height = match self.module.max_stack_heights[defined].get() {
+            Some(height) => *height,
+            None => {
+                let height = validate_body(module, func, &body)
+                    .with_context(|| format!("compile {}", module.describe_func(idx)))?;
+                *self.module.max_stack_heights[defined].get_or_init(|| height)
+            }
+        };
+        let blocks = match self.module.block_targets[defined].get() {
+            Some(blocks) => blocks.clone(),
+            None => {
+                let blocks =
+                    block_targets(&body).with_context(|| format!("compile function {idx}"))?;
+                self.module.block_targets[defined]
+                    .get_or_init(|| Rc::new(blocks))
+                    .clone()
+            }
+        };
+
+        Ok(Function {
+            idx,
+            max_stack_height,
+            instance: self.clone(),
+            body,
+            blocks,
+        })
+    }
+}
+
+/// A handle to a global living in a [`Store`].
+pub struct Global {
+    addr: usize,
+    ty: GlobalType,
+}
+
+impl Global {
+    pub fn ty(&self) -> &GlobalType {
+        &self.ty
+    }
+
+    pub fn get(&self, store: &Store) -> Value {
+        store.globals[self.addr]
+    }
+
+    pub fn set(&self, store: &mut Store, value: Value) -> Result<()> {
+        if !self.ty.mutable {
+            bail!("cannot set an immutable global");
+        }
+        if !self.ty.val.accepts(&value) {
+            bail!("global has type {:?}, got {value:?}", self.ty.val);
+        }
+
+        store.globals[self.addr] = value;
+        Ok(())
+    }
+}
+
+/// A handle to a linear memory living in a [`Store`].
+pub struct Memory {
+    addr: usize,
+}
+
+impl Memory {
+    /// The contents of the memory.
+    pub fn data<'a>(&self, store: &'a Store) -> &'a [u8] {
+        &store.memories[self.addr]
+    }
+
+    pub fn data_mut<'a>(&self, store: &'a mut Store) -> &'a mut [u8] {
+        &mut store.memories[self.addr]
+    }
+
+    /// Size of the memory in bytes.
+    pub fn size(&self, store: &Store) -> usize {
+        self.data(store).len()
+    }
+
+    /// Fills `buf` with the bytes starting at `offset`.
+    pub fn read(&self, store: &Store, offset: usize, buf: &mut [u8]) -> Result<()> {
+        match offset
+            .checked_add(buf.len())
+            .and_then(|end| self.data(store).get(offset..end))
+        {
+            Some(bytes) => buf.copy_from_slice(bytes),
+            None => bail!(TrapKind::OutOfBounds),
+        }
+        Ok(())
+    }
+
+    /// Writes `bytes` starting at `offset`.
+    pub fn write(&self, store: &mut Store, offset: usize, bytes: &[u8]) -> Result<()> {
+        match offset
+            .checked_add(bytes.len())
+            .and_then(|end| self.data_mut(store).get_mut(offset..end))
+        {
+            Some(dst) => dst.copy_from_slice(bytes),
+            None => bail!(TrapKind::OutOfBounds),
+        }
+        Ok(())
+    }
+
+    /// Reads `count` little-endian i32s starting at `ptr`.
+    pub fn read_i32_slice(&self, store: &Store, ptr: u32, count: usize) -> Result<Vec<i32>> {
+        let bytes = self.slice(store, ptr, count, 4)?;
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|b| i32::from_le_bytes(b.try_into().expect("chunks of 4 bytes")))
+            .collect())
+    }
+
+    /// Writes `values` as little-endian i32s starting at `ptr`.
+    pub fn write_i32_slice(&self, store: &mut Store, ptr: u32, values: &[i32]) -> Result<()> {
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        self.write(store, ptr as usize, &bytes)
+    }
+
+    /// Reads `count` little-endian f64s starting at `ptr`.
+    pub fn read_f64_slice(&self, store: &Store, ptr: u32, count: usize) -> Result<Vec<f64>> {
+        let bytes = self.slice(store, ptr, count, 8)?;
+        Ok(bytes
+            .chunks_exact(8)
+            .map(|b| f64::from_le_bytes(b.try_into().expect("chunks of 8 bytes")))
+            .collect())
+    }
+
+    /// Writes `values` as little-endian f64s starting at `ptr`.
+    pub fn write_f64_slice(&self, store: &mut Store, ptr: u32, values: &[f64]) -> Result<()> {
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        self.write(store, ptr as usize, &bytes)
+    }
+
+    // The bytes of `count` values of `size` bytes starting at `ptr`.
+    fn slice<'a>(&self, store: &'a Store, ptr: u32, count: usize, size: usize) -> Result<&'a [u8]> {
+        let start = ptr as usize;
+        match count
+            .checked_mul(size)
+            .and_then(|len| start.checked_add(len))
+            .and_then(|end| self.data(store).get(start..end))
+        {
+            Some(bytes) => Ok(bytes),
+            None => bail!(TrapKind::OutOfBounds),
+        }
+    }
+
+    /// Reads the `len` bytes starting at `ptr` as a UTF-8 string.
+    pub fn read_string(&self, store: &Store, ptr: u32, len: u32) -> Result<String> {
+        let mut bytes = vec![0; len as usize];
+        self.read(store, ptr as usize, &mut bytes)?;
+        String::from_utf8(bytes).with_context(|| format!("string at {ptr} isn't valid UTF-8"))
+    }
+
+    /// Reads the null-terminated UTF-8 string starting at `ptr`, the null
+    /// byte is left out.
+    pub fn read_cstr(&self, store: &Store, ptr: u32) -> Result<String> {
+        let bytes = self.data(store).get(ptr as usize..).unwrap_or_default();
+        let Some(len) = bytes.iter().position(|b| *b == 0) else {
+            return Err(TrapKind::OutOfBounds.with_detail(format!(
+                "string at {ptr} isn't terminated before the end of the memory"
+            )));
+        };
+        std::str::from_utf8(&bytes[..len])
+            .map(str::to_string)
+            .with_context(|| format!("string at {ptr} isn't valid UTF-8"))
+    }
 }
 
 pub struct Function {
-    body: Vec<Instr>,
+    idx: u32,
+    max_stack_height: usize,
+    // The instance the function belongs to, to look up globals and callees.
+    instance: Exports,
+    body: Rc<[Instr]>,
+    blocks: Rc<BlockTargets>,
 }
 
 impl Function {
-    pub fn call(&self, _store: &mut Store, locals: &[Value]) -> Result<Value> {
-        let mut stack = vec![];
+    /// Runs the function, returning its results in declaration order.
+    pub fn call(&self, store: &mut Store, locals: &[Value]) -> Result<Vec<Value>, WasmError> {
+        self.call_raw(store, locals).map_err(WasmError::from_error)
+    }
 
-        for instr in &self.body {
-            match instr {
-                Instr::LocalGet(n) => stack.push(locals[*n as usize]),
-                Instr::I32Add => {
-                    let result = self.i32_add(&mut stack)?;
-                    stack.push(result);
-                }
-                Instr::I32Mul => {
-                    let result = self.i32_mul(&mut stack)?;
-                    stack.push(result);
-                }
-                Instr::End => break,
-                Instr::Call(_) => todo!(),
-                Instr::ConstF64(_) => todo!(),
-                Instr::LoadI32(_) => todo!(),
-                Instr::DivI32U => todo!(),
+    /// Runs the function with the arguments given by the names of its params
+    /// in the `name` custom section. Every param must be given, and only
+    /// them.
+    pub fn call_named(
+        &self,
+        store: &mut Store,
+        args: &HashMap<String, Value>,
+    ) -> Result<Vec<Value>, WasmError> {
+        let names: Vec<_> = (0..self.ty().params.len() as u32)
+            .map(|idx| self.func().local_name(idx))
+            .collect();
+        if let Some(unknown) = args.keys().find(|arg| !names.contains(&Some(arg.as_str()))) {
+            return Err(WasmError::Validation(format!("unknown param {unknown}")));
+        }
+
+        let mut positional = Vec::new();
+        for (idx, name) in names.into_iter().enumerate() {
+            let Some(name) = name else {
+                return Err(WasmError::Validation(format!("param {idx} has no name")));
+            };
+            let Some(arg) = args.get(name) else {
+                return Err(WasmError::Validation(format!("missing argument {name}")));
+            };
+            positional.push(*arg);
+        }
+
+        self.call(store, &positional)
+    }
+
+    // Calls from the interpreter keep the error as it is, for the callers to
+    // add their context and core dump frames.
+    fn call_raw(&self, store: &mut Store, args: &[Value]) -> Result<Vec<Value>> {
+        if store.call_depth >= MAX_CALL_DEPTH {
+            return Err(TrapKind::StackExhausted
+                .with_detail(format!("more than {MAX_CALL_DEPTH} nested calls")));
+        }
+        store.call_depth += 1;
+        let result = match self.run(store, args) {
+            Ok((Some(idx), stack)) => self.tail_calls(store, idx, stack),
+            Ok((None, stack)) => Ok(stack),
+            Err(e) => Err(e),
+        };
+        store.call_depth -= 1;
+
+        let mut stack = result?;
+        let results_len = self.ty().results.len();
+        let Some(results_start) = stack.len().checked_sub(results_len) else {
+            bail!(
+                "function {} should return {results_len} values but returned {}",
+                self.idx,
+                stack.len()
+            );
+        };
+
+        Ok(stack.split_off(results_start))
+    }
+
+    // Runs the function tail called with the arguments on top of `stack`, then
+    // the ones it tail calls in turn. They replace the caller instead of
+    // nesting, so they don't count towards the call depth. Kept apart from
+    // `call_raw` so that its frame is only on the native stack after a tail
+    // call.
+    #[inline(never)]
+    fn tail_calls(
+        &self,
+        store: &mut Store,
+        mut idx: u32,
+        mut stack: Vec<Value>,
+    ) -> Result<Vec<Value>> {
+        loop {
+            // Validation made sure the arguments are on the stack
+            let params_len = self
+                .instance
+                .module
+                .module
+                .func_type(idx)
+                .map_or(0, |ty| ty.params.len());
+            let args = stack.split_off(stack.len().saturating_sub(params_len));
+            if let Some(host) = self.instance.host_funcs.get(idx as usize) {
+                return Exports::call_host(host, idx, &args);
+            }
+
+            match self.instance.function(idx)?.run(store, &args)? {
+                (Some(next), rest) => (idx, stack) = (next, rest),
+                (None, rest) => return Ok(rest),
             }
         }
+    }
 
-        Ok(stack.pop().unwrap())
+    // Functions are only created for functions defined by the module.
+    fn func(&self) -> &Func {
+        self.instance
+            .module
+            .module
+            .defined_func(self.idx)
+            .expect("function is defined by the module")
     }
 
-    fn i32_add(&self, stack: &mut Vec<Value>) -> Result<Value> {
-        match (stack.pop(), stack.pop()) {
-            (Some(Value::I32(left)), Some(Value::I32(right))) => Ok(Value::I32(left + right)),
-            _ => bail!("wrong types for i32_add"),
+    pub fn ty(&self) -> &FuncType {
+        self.func().ty()
+    }
+
+    // Runs the body, returning what is left on the operand stack, along with
+    // the function to tail call if the body ends with a tail call.
+    fn run(&self, store: &mut Store, args: &[Value]) -> Result<(Option<u32>, Vec<Value>)> {
+        // Checked before allocating the locals, there can be a lot of them
+        if let Some(max) = store.config.max_call_memory_slots {
+            let num_locals = args.len() + self.func().locals().len();
+            check_call_memory(max, self.max_stack_height, num_locals)?;
+        }
+        let mut locals = self.locals(args)?;
+
+        if let Some(profiler) = store.profiler.as_mut() {
+            profiler.enter(self.idx);
+        }
+        let mut stack = Vec::with_capacity(self.max_stack_height);
+        let mut offset = 0;
+        let result = self.run_body(store, &mut locals, &mut stack, &mut offset);
+        if let Some(profiler) = store.profiler.as_mut() {
+            profiler.exit();
+        }
+
+        match result {
+            Ok(tail_call) => Ok((tail_call, stack)),
+            Err(e) if store.coredumps => {
+                let frame = coredump::Frame {
+                    func: self.idx,
+                    offset,
+                    locals,
+                    stack,
+                };
+                Err(self.add_coredump_frame(store, e, frame))
+            }
+            Err(e) => Err(e),
         }
     }
 
-    fn i32_mul(&self, stack: &mut Vec<Value>) -> Result<Value> {
-        match (stack.pop(), stack.pop()) {
-            (Some(Value::I32(left)), Some(Value::I32(right))) => {
-                Ok(Value::I32(left.saturating_mul(right)))
+    // The arguments followed by the declared locals.
+    fn locals(&self, args: &[Value]) -> Result<Vec<Value>> {
+        let params = &self.ty().params;
+        if args.len() != params.len() {
+            bail!(
+                "{} expects {} arguments, got {}",
+                self.instance.module.module.describe_func(self.idx),
+                params.len(),
+                args.len()
+            );
+        }
+        for (i, (ty, arg)) in params.iter().zip(args).enumerate() {
+            if !ty.accepts(arg) {
+                bail!(
+                    "{} expects argument {i} of type {ty:?}, got {arg:?}",
+                    self.instance.module.module.describe_func(self.idx)
+                );
+            }
+        }
+
+        let mut locals = Vec::with_capacity(args.len() + self.func().locals().len());
+        locals.extend_from_slice(args);
+        for ty in self.func().locals() {
+            match Value::zero(ty) {
+                Some(value) => locals.push(value),
+                None => bail!("locals of type {ty:?} are not supported"),
             }
-            _ => bail!("wrong types for i32_add"),
         }
+
+        Ok(locals)
     }
-}
 
-#[derive(Copy, Clone, Debug)]
-pub enum Value {
-    I32(i32),
-}
+    // Records `frame` in the core dump attached to `error`, the frames of the
+    // callers are added as the error is passed up the call stack.
+    fn add_coredump_frame(
+        &self,
+        store: &Store,
+        mut error: anyhow::Error,
+        frame: coredump::Frame,
+    ) -> anyhow::Error {
+        if let Some(dump) = error.downcast_mut::<CoreDump>() {
+            dump.frames.push(frame);
+            return error;
+        }
 
-impl Display for Value {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Value::I32(n) => write!(f, "{n}"),
+        let dump = CoreDump {
+            globals: self
+                .instance
+                .globals
+                .iter()
+                .map(|addr| store.globals[*addr])
+                .collect(),
+            memories: self
+                .instance
+                .memories
+                .iter()
+                .map(|addr| store.memories[*addr].clone())
+                .collect(),
+            frames: vec![frame],
+        };
+        error.context(dump)
+    }
+
+    // Runs the body, leaving the offset of the instruction being executed in
+    // `offset` so that it is known when it traps. Returns the function to tail
+    // call, if the body ends with one, its arguments being on top of `stack`.
+    fn run_body(
+        &self,
+        store: &mut Store,
+        locals: &mut [Value],
+        stack: &mut Vec<Value>,
+        offset: &mut usize,
+    ) -> Result<Option<u32>> {
+        // The blocks the running code is in, innermost last
+        let mut labels = Vec::new();
+        let mut pc = 0;
+
+        while let Some(instr) = self.body.get(pc) {
+            *offset = pc;
+            pc += 1;
+
+            if let Some(trace) = store.trace.as_mut() {
+                self.trace(trace, *offset, instr)?;
+            }
+            if let Some(profiler) = store.profiler.as_mut() {
+                profiler.count();
+            }
+            if let Some(deadline) = store.deadline {
+                store.check_deadline(deadline)?;
+            }
+
+            if !store.interceptors.is_empty() && self.intercept(store, instr, stack)? {
+                continue;
+            }
+
+            // Catches the interpreter disagreeing with the decoder about
+            // operand types, compiled out of release builds.
+            if cfg!(debug_assertions) {
+                check_operands(instr, stack)?;
+            }
+
+            match instr {
+                Instr::Block(_)
+                | Instr::Loop(_)
+                | Instr::If(_)
+                | Instr::Else
+                | Instr::Br(_)
+                | Instr::BrIf(_)
+                | Instr::Return
+                | Instr::End => match self.control_flow(instr, pc, &mut labels, stack) {
+                    Ok(Some(next)) => pc = next,
+                    Ok(None) => break,
+                    Err(e) => return Err(self.trap_context(e)),
+                },
+                // Traps in the callee already say where they happened
+                Instr::Call(idx) => self.call_function(store, *idx, stack)?,
+                Instr::ReturnCall(idx) => return Ok(Some(*idx)),
+                _ => {
+                    if let Err(e) = self.execute(store, instr, stack, locals) {
+                        return Err(self.trap_context(e));
+                    }
+                }
+            }
+
+            if let Some(max) = store.config.max_value_stack {
+                check_value_stack(max, stack.len())?;
+            }
+            if let Some(max) = store.config.max_call_memory_slots {
+                check_call_memory(max, stack.len(), locals.len())?;
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Says in which function a trap happened.
+    #[cold]
+    #[inline(never)]
+    fn trap_context(&self, error: anyhow::Error) -> anyhow::Error {
+        let func = self.instance.module.module.describe_func(self.idx);
+        error.context(format!("trap in {func}"))
+    }
+
+    fn trace(&self, trace: &mut dyn Write, offset: usize, instr: &Instr) -> Result<()> {
+        writeln!(trace, "{} {offset} {:#04x}", self.idx, instr.opcode())?;
+        Ok(())
+    }
+
+    // Executes a block, loop, if, else, branch, return or end, `next` being the offset of the
+    // following instruction. Returns the offset of the instruction to run
+    // next, `None` to return from the function. Not inlined for the same
+    // reason as `execute`.
+    #[inline(never)]
+    fn control_flow(
+        &self,
+        instr: &Instr,
+        next: usize,
+        labels: &mut Vec<Label>,
+        stack: &mut Vec<Value>,
+    ) -> Result<Option<usize>> {
+        match instr {
+            Instr::Block(ty) => {
+                let (params, results) = self.block_arity(ty)?;
+                labels.push(Label {
+                    target: self.blocks.ends[&(next - 1)],
+                    height: operands_height(stack, params)?,
+                    arity: results,
+                });
+            }
+            Instr::If(ty) => {
+                let Some(Value::I32(condition)) = stack.pop() else {
+                    bail!("wrong types for if, expected an i32 condition");
+                };
+                let (params, results) = self.block_arity(ty)?;
+                let start = next - 1;
+                let end = self.blocks.ends[&start];
+                labels.push(Label {
+                    target: end,
+                    height: operands_height(stack, params)?,
+                    arity: results,
+                });
+
+                if condition == 0 {
+                    // Without an else, straight to the `end` popping the label
+                    return match self.blocks.elses.get(&start) {
+                        Some(else_) => Ok(Some(else_ + 1)),
+                        None => Ok(Some(end)),
+                    };
+                }
+            }
+            // Reached at the end of the then arm, which skips the else arm
+            Instr::Else => match labels.last() {
+                Some(label) => return Ok(Some(label.target)),
+                None => bail!("else outside of an if"),
+            },
+            Instr::Loop(ty) => {
+                let (params, _) = self.block_arity(ty)?;
+                labels.push(Label {
+                    target: next,
+                    height: operands_height(stack, params)?,
+                    arity: params,
+                });
+            }
+            Instr::Br(depth) => return branch(labels, stack, *depth),
+            Instr::BrIf(depth) => {
+                let Some(Value::I32(condition)) = stack.pop() else {
+                    bail!("wrong types for br_if, expected an i32 condition");
+                };
+                if condition != 0 {
+                    return branch(labels, stack, *depth);
+                }
+            }
+            Instr::Return => return Ok(None),
+            Instr::End => {
+                // The end of the function body otherwise
+                if labels.pop().is_none() {
+                    return Ok(None);
+                }
+            }
+            _ => unreachable!(),
         }
+
+        Ok(Some(next))
+    }
+
+    // Executes any instruction but the ones affecting control flow. It is
+    // kept out of `run_body` so that its large frame isn't on the native
+    // stack once for every nested call.
+    #[inline(never)]
+    fn execute(
+        &self,
+        store: &mut Store,
+        instr: &Instr,
+        stack: &mut Vec<Value>,
+        locals: &mut [Value],
+    ) -> Result<()> {
+        match instr {
+            Instr::LocalGet(n) => match locals.get(*n as usize) {
+                Some(value) => stack.push(*value),
+                None => bail!("unknown local {n}"),
+            },
+            Instr::LocalSet(n) => {
+                let Some(value) = stack.pop() else {
+                    bail!("local.set on an empty stack");
+                };
+                if store.config.check_slot_types {
+                    self.check_local_type(*n, &value)?;
+                }
+                match locals.get_mut(*n as usize) {
+                    Some(local) => *local = value,
+                    None => bail!("unknown local {n}"),
+                }
+            }
+            Instr::LocalTee(n) => {
+                let Some(value) = stack.last() else {
+                    bail!("local.tee on an empty stack");
+                };
+                if store.config.check_slot_types {
+                    self.check_local_type(*n, value)?;
+                }
+                match locals.get_mut(*n as usize) {
+                    Some(local) => *local = *value,
+                    None => bail!("unknown local {n}"),
+                }
+            }
+            Instr::GlobalGet(idx) => {
+                let addr = self.global_addr(*idx)?;
+                stack.push(store.globals[addr]);
+            }
+            Instr::GlobalSet(idx) => {
+                let addr = self.global_addr(*idx)?;
+                let Some(value) = stack.pop() else {
+                    bail!("global.set on an empty stack");
+                };
+                if store.config.check_slot_types {
+                    let ty = &self.instance.module.module.globals[*idx as usize].ty.val;
+                    if !ty.accepts(&value) {
+                        bail!("global.set of {value:?} to global {idx} of type {ty:?}");
+                    }
+                }
+                store.globals[addr] = value;
+            }
+            Instr::ConstI32(n) => stack.push(Value::I32(*n)),
+            Instr::ConstI64(n) => stack.push(Value::I64(*n)),
+            Instr::I32Eqz => {
+                let Some(Value::I32(n)) = stack.pop() else {
+                    bail!("wrong types for i32_eqz, expected an i32 operand");
+                };
+                stack.push(Value::I32((n == 0) as i32));
+            }
+            Instr::I32Eq => {
+                let result = self.i32_compare(stack, "i32_eq", |left, right| left == right)?;
+                stack.push(result);
+            }
+            Instr::I32Ne => {
+                let result = self.i32_compare(stack, "i32_ne", |left, right| left != right)?;
+                stack.push(result);
+            }
+            Instr::I32LtS => {
+                let result = self.i32_compare(stack, "i32_lt_s", |left, right| left < right)?;
+                stack.push(result);
+            }
+            Instr::I32LtU => {
+                let result = self.i32_compare(stack, "i32_lt_u", |left, right| {
+                    (left as u32) < right as u32
+                })?;
+                stack.push(result);
+            }
+            Instr::I32GtS => {
+                let result = self.i32_compare(stack, "i32_gt_s", |left, right| left > right)?;
+                stack.push(result);
+            }
+            Instr::I32GtU => {
+                let result =
+                    self.i32_compare(stack, "i32_gt_u", |left, right| left as u32 > right as u32)?;
+                stack.push(result);
+            }
+            Instr::I32LeS => {
+                let result = self.i32_compare(stack, "i32_le_s", |left, right| left <= right)?;
+                stack.push(result);
+            }
+            Instr::I32LeU => {
+                let result =
+                    self.i32_compare(stack, "i32_le_u", |left, right| left as u32 <= right as u32)?;
+                stack.push(result);
+            }
+            Instr::I32GeS => {
+                let result = self.i32_compare(stack, "i32_ge_s", |left, right| left >= right)?;
+                stack.push(result);
+            }
+            Instr::I32GeU => {
+                let result =
+                    self.i32_compare(stack, "i32_ge_u", |left, right| left as u32 >= right as u32)?;
+                stack.push(result);
+            }
+            Instr::I32Sub => {
+                let result =
+                    self.i32_binary(stack, "i32_sub", |left, right| Ok(left.wrapping_sub(right)))?;
+                stack.push(result);
+            }
+            Instr::I32DivS => {
+                let result =
+                    self.i32_binary(stack, "i32_div_s", |left, right| match (left, right) {
+                        (_, 0) => bail!(TrapKind::DivByZero),
+                        (i32::MIN, -1) => bail!(TrapKind::IntegerOverflow),
+                        _ => Ok(left / right),
+                    })?;
+                stack.push(result);
+            }
+            Instr::I32RemS => {
+                let result = self.i32_binary(stack, "i32_rem_s", |left, right| {
+                    match right {
+                        0 => bail!(TrapKind::DivByZero),
+                        // `i32::MIN % -1` overflows in Rust but is 0 in wasm
+                        _ => Ok(left.wrapping_rem(right)),
+                    }
+                })?;
+                stack.push(result);
+            }
+            Instr::I32RemU => {
+                let result = self.i32_binary(stack, "i32_rem_u", |left, right| match right {
+                    0 => bail!(TrapKind::DivByZero),
+                    _ => Ok((left as u32 % right as u32) as i32),
+                })?;
+                stack.push(result);
+            }
+            Instr::I32And => {
+                let result = self.i32_binary(stack, "i32_and", |left, right| Ok(left & right))?;
+                stack.push(result);
+            }
+            Instr::I32Or => {
+                let result = self.i32_binary(stack, "i32_or", |left, right| Ok(left | right))?;
+                stack.push(result);
+            }
+            Instr::I32Xor => {
+                let result = self.i32_binary(stack, "i32_xor", |left, right| Ok(left ^ right))?;
+                stack.push(result);
+            }
+            Instr::I32Shl => {
+                let result = self.i32_binary(stack, "i32_shl", |left, right| {
+                    Ok(left.wrapping_shl(right as u32))
+                })?;
+                stack.push(result);
+            }
+            Instr::I32ShrS => {
+                let result = self.i32_binary(stack, "i32_shr_s", |left, right| {
+                    Ok(left.wrapping_shr(right as u32))
+                })?;
+                stack.push(result);
+            }
+            Instr::I32ShrU => {
+                let result = self.i32_binary(stack, "i32_shr_u", |left, right| {
+                    Ok((left as u32).wrapping_shr(right as u32) as i32)
+                })?;
+                stack.push(result);
+            }
+            Instr::I32Add => {
+                let result = self.i32_add(stack)?;
+                stack.push(result);
+            }
+            Instr::I32Mul => {
+                let result = self.i32_mul(stack)?;
+                stack.push(result);
+            }
+            Instr::I64Add => {
+                let result = self.i64_add(stack)?;
+                stack.push(result);
+            }
+            Instr::I64Sub => {
+                let result = self.i64_sub(stack)?;
+                stack.push(result);
+            }
+            Instr::I64Mul => {
+                let result = self.i64_mul(stack)?;
+                stack.push(result);
+            }
+            Instr::F64Add => {
+                let result = self.f64_add(stack)?;
+                stack.push(result);
+            }
+            Instr::F64Sub => {
+                let result = self.f64_sub(stack)?;
+                stack.push(result);
+            }
+            Instr::F64Mul => {
+                let result = self.f64_mul(stack)?;
+                stack.push(result);
+            }
+            Instr::F64Div => {
+                let result = self.f64_div(stack)?;
+                stack.push(result);
+            }
+            // Handled by `run_body`
+            Instr::Block(_)
+            | Instr::Loop(_)
+            | Instr::If(_)
+            | Instr::Else
+            | Instr::Br(_)
+            | Instr::BrIf(_)
+            | Instr::Return
+            | Instr::End
+            | Instr::Call(_)
+            | Instr::ReturnCall(_) => unreachable!(),
+            Instr::Unreachable => bail!(TrapKind::Unreachable),
+            Instr::Nop => {}
+            Instr::ReturnCallIndirect(_, table) => bail!("unknown table {table}"),
+            Instr::ConstF64(n) => stack.push(Value::F64(*n)),
+            Instr::I32Load(memarg) => {
+                let Some(Value::I32(addr)) = stack.pop() else {
+                    bail!("wrong types for i32_load, expected an i32 address");
+                };
+                let (mem, start) = self.memory_access(store, addr, memarg, 4)?;
+                let bytes = &store.memories[mem][start..start + 4];
+                stack.push(Value::I32(i32::from_le_bytes(bytes.try_into().unwrap())));
+            }
+            Instr::I32Load8U(memarg) => {
+                let Some(Value::I32(addr)) = stack.pop() else {
+                    bail!("wrong types for i32_load8_u, expected an i32 address");
+                };
+                let (mem, start) = self.memory_access(store, addr, memarg, 1)?;
+                stack.push(Value::I32(store.memories[mem][start] as i32));
+            }
+            Instr::MemorySize => {
+                let mem = self.memory_addr()?;
+                stack.push(Value::I32((store.memories[mem].len() / PAGE_SIZE) as i32));
+            }
+            Instr::MemoryGrow => {
+                let Some(Value::I32(delta)) = stack.pop() else {
+                    bail!("wrong types for memory_grow, expected an i32 delta");
+                };
+                let mem = self.memory_addr()?;
+                let max = max_pages(&self.instance.module.module.memories[0], &store.config);
+
+                // Growing fails without trapping, leaving the memory as is
+                let pages = store.memories[mem].len() / PAGE_SIZE;
+                match (pages as u32).checked_add(delta as u32) {
+                    Some(new_pages) if new_pages <= max => {
+                        let data = &mut store.memories[mem];
+                        let additional = new_pages as usize * PAGE_SIZE - data.len();
+                        // The host running out of memory isn't the guest's
+                        // problem to crash on, it just can't grow
+                        if data.try_reserve_exact(additional).is_err() {
+                            stack.push(Value::I32(-1));
+                        } else {
+                            data.resize(new_pages as usize * PAGE_SIZE, 0);
+                            stack.push(Value::I32(pages as i32));
+                        }
+                    }
+                    _ => stack.push(Value::I32(-1)),
+                }
+            }
+            Instr::I32Store(memarg) => {
+                let (Some(Value::I32(value)), Some(Value::I32(addr))) = (stack.pop(), stack.pop())
+                else {
+                    bail!("wrong types for i32_store, expected an i32 address and value");
+                };
+                let (mem, start) = self.memory_access(store, addr, memarg, 4)?;
+                store.memories[mem][start..start + 4].copy_from_slice(&value.to_le_bytes());
+            }
+            Instr::DivI32U => {
+                let result = self.i32_div_u(stack)?;
+                stack.push(result);
+            }
+            Instr::F32DemoteF64 => match stack.pop() {
+                Some(Value::F64(n)) => stack.push(Value::F32(n as f32)),
+                _ => bail!("wrong types for f32_demote_f64"),
+            },
+            Instr::F64PromoteF32 => match stack.pop() {
+                Some(Value::F32(n)) => stack.push(Value::F64(n as f64)),
+                _ => bail!("wrong types for f64_promote_f32"),
+            },
+            Instr::I32ReinterpretF32 => match stack.pop() {
+                Some(Value::F32(n)) => stack.push(Value::I32(n.to_bits() as i32)),
+                _ => bail!("wrong types for i32_reinterpret_f32"),
+            },
+            Instr::I64ReinterpretF64 => match stack.pop() {
+                Some(Value::F64(n)) => stack.push(Value::I64(n.to_bits() as i64)),
+                _ => bail!("wrong types for i64_reinterpret_f64"),
+            },
+            Instr::F32ReinterpretI32 => match stack.pop() {
+                Some(Value::I32(n)) => stack.push(Value::F32(f32::from_bits(n as u32))),
+                _ => bail!("wrong types for f32_reinterpret_i32"),
+            },
+            Instr::F64ReinterpretI64 => match stack.pop() {
+                Some(Value::I64(n)) => stack.push(Value::F64(f64::from_bits(n as u64))),
+                _ => bail!("wrong types for f64_reinterpret_i64"),
+            },
+            Instr::MemoryInit(idx) => {
+                let (len, src, dst) = pop_i32_triple(stack, "memory_init")?;
+                let mem = self.memory_addr()?;
+                let Some(data) = self.instance.datas.get(*idx as usize) else {
+                    bail!("unknown data segment {idx}");
+                };
+                let src = checked_range(&store.datas[*data], src, len)?;
+                let dst = checked_range(&store.memories[mem], dst, len)?;
+                store.memories[mem][dst].copy_from_slice(&store.datas[*data][src]);
+            }
+            Instr::DataDrop(idx) => {
+                let Some(data) = self.instance.datas.get(*idx as usize) else {
+                    bail!("unknown data segment {idx}");
+                };
+                // A dropped segment behaves as an empty one
+                store.datas[*data] = Vec::new();
+            }
+            Instr::MemoryCopy => {
+                let (len, src, dst) = pop_i32_triple(stack, "memory_copy")?;
+                let mem = self.memory_addr()?;
+                let src = checked_range(&store.memories[mem], src, len)?;
+                let dst = checked_range(&store.memories[mem], dst, len)?;
+                store.memories[mem].copy_within(src, dst.start);
+            }
+            Instr::MemoryFill => {
+                let (len, value, dst) = pop_i32_triple(stack, "memory_fill")?;
+                let mem = self.memory_addr()?;
+                let dst = checked_range(&store.memories[mem], dst, len)?;
+                store.memories[mem][dst].fill(value as u8);
+            }
+            // Memory accesses are never reordered, there is a single thread
+            Instr::AtomicFence => {}
+        }
+
+        Ok(())
+    }
+
+    // Runs the interceptor registered for this instruction, if any. The
+    // interceptor is taken out of the store while it runs since it gets the
+    // store itself.
+    fn intercept(&self, store: &mut Store, instr: &Instr, stack: &mut Vec<Value>) -> Result<bool> {
+        let opcode = instr.opcode();
+        let Some(mut interceptor) = store.interceptors.remove(&opcode) else {
+            return Ok(false);
+        };
+
+        let handled = interceptor(instr, stack, store);
+        // Keep the interceptor it might have registered in the meantime
+        store.interceptors.entry(opcode).or_insert(interceptor);
+        handled
+    }
+
+    // Pops the callee's arguments off `stack`, runs it and pushes its results.
+    fn call_function(&self, store: &mut Store, idx: u32, stack: &mut Vec<Value>) -> Result<()> {
+        let Some(params_len) = self
+            .instance
+            .module
+            .module
+            .func_type(idx)
+            .map(|ty| ty.params.len())
+        else {
+            bail!("unknown function {idx}");
+        };
+
+        let Some(args_start) = stack.len().checked_sub(params_len) else {
+            bail!(
+                "call to function {idx} needs {params_len} arguments but the stack only has {}",
+                stack.len()
+            );
+        };
+        let args = stack.split_off(args_start);
+
+        stack.extend(self.instance.call(store, idx, &args)?);
+
+        Ok(())
+    }
+
+    fn check_local_type(&self, idx: u32, value: &Value) -> Result<()> {
+        let func = self.func();
+        let ty = match func.ty().params.get(idx as usize) {
+            Some(ty) => Some(ty),
+            None => func.locals().get(idx as usize - func.ty().params.len()),
+        };
+        match ty {
+            Some(ty) if ty.accepts(value) => Ok(()),
+            Some(ty) => bail!("store of {value:?} to local {idx} of type {ty:?}"),
+            None => bail!("unknown local {idx}"),
+        }
+    }
+
+    // Checks a load or store of `len` bytes at `addr`, returning the store
+    // address of the memory and the offset of the first byte in it.
+    fn memory_access(
+        &self,
+        store: &Store,
+        addr: i32,
+        memarg: &MemArg,
+        len: usize,
+    ) -> Result<(usize, usize)> {
+        let mem = self.memory_addr()?;
+
+        // The address is unsigned and adding the offset can go past 32 bits
+        let start = addr as u32 as usize + memarg.offset as usize;
+        match start.checked_add(len) {
+            Some(end) if end <= store.memories[mem].len() => {}
+            _ => bail!(TrapKind::OutOfBounds),
+        }
+        // `align` is the log2 of the alignment
+        if store.config.strict_alignment && start.trailing_zeros() < memarg.align {
+            return Err(TrapKind::Misaligned.with_detail(format!(
+                "address {start} isn't aligned to {} bytes",
+                1 << memarg.align
+            )));
+        }
+
+        Ok((mem, start))
+    }
+
+    // Store address of memory 0, the only one instructions can refer to.
+    fn memory_addr(&self) -> Result<usize> {
+        match self.instance.memories.first() {
+            Some(addr) => Ok(*addr),
+            None => bail!("unknown memory 0"),
+        }
+    }
+
+    fn block_arity(&self, ty: &BlockType) -> Result<(usize, usize)> {
+        match self.instance.module.module.block_arity(ty) {
+            Some(arity) => Ok(arity),
+            None => bail!("unknown block type {ty:?}"),
+        }
+    }
+
+    fn global_addr(&self, idx: u32) -> Result<usize> {
+        match self.instance.globals.get(idx as usize) {
+            Some(addr) => Ok(*addr),
+            None => bail!("unknown global {idx}"),
+        }
+    }
+
+    // Pops two i32 operands and pushes 1 if `op` holds for them, 0 otherwise.
+    // `name` is only used in the error.
+    fn i32_compare(
+        &self,
+        stack: &mut Vec<Value>,
+        name: &str,
+        op: fn(i32, i32) -> bool,
+    ) -> Result<Value> {
+        match (stack.pop(), stack.pop()) {
+            (Some(Value::I32(right)), Some(Value::I32(left))) => {
+                Ok(Value::I32(op(left, right) as i32))
+            }
+            _ => bail!("wrong types for {name}, expected two i32 operands"),
+        }
+    }
+
+    // Pops two i32 operands and pushes the result of `op` on them, the
+    // shift counts of `wrapping_shl` and `wrapping_shr` are masked like wasm
+    // does. `name` is only used in the error.
+    fn i32_binary(
+        &self,
+        stack: &mut Vec<Value>,
+        name: &str,
+        op: fn(i32, i32) -> Result<i32>,
+    ) -> Result<Value> {
+        match (stack.pop(), stack.pop()) {
+            (Some(Value::I32(right)), Some(Value::I32(left))) => Ok(Value::I32(op(left, right)?)),
+            _ => bail!("wrong types for {name}, expected two i32 operands"),
+        }
+    }
+
+    fn i32_add(&self, stack: &mut Vec<Value>) -> Result<Value> {
+        match (stack.pop(), stack.pop()) {
+            (Some(Value::I32(right)), Some(Value::I32(left))) => {
+                Ok(Value::I32(left.wrapping_add(right)))
+            }
+            _ => bail!("wrong types for i32_add, expected two i32 operands"),
+        }
+    }
+
+    fn i32_mul(&self, stack: &mut Vec<Value>) -> Result<Value> {
+        match (stack.pop(), stack.pop()) {
+            (Some(Value::I32(right)), Some(Value::I32(left))) => {
+                Ok(Value::I32(left.wrapping_mul(right)))
+            }
+            _ => bail!("wrong types for i32_mul, expected two i32 operands"),
+        }
+    }
+
+    fn i32_div_u(&self, stack: &mut Vec<Value>) -> Result<Value> {
+        // The divisor is on top of the stack
+        match (stack.pop(), stack.pop()) {
+            (Some(Value::I32(0)), Some(Value::I32(_))) => bail!(TrapKind::DivByZero),
+            (Some(Value::I32(right)), Some(Value::I32(left))) => {
+                Ok(Value::I32((left as u32 / right as u32) as i32))
+            }
+            _ => bail!("wrong types for i32_div_u, expected two i32 operands"),
+        }
+    }
+
+    fn i64_add(&self, stack: &mut Vec<Value>) -> Result<Value> {
+        match (stack.pop(), stack.pop()) {
+            (Some(Value::I64(right)), Some(Value::I64(left))) => {
+                Ok(Value::I64(left.wrapping_add(right)))
+            }
+            _ => bail!("wrong types for i64_add, expected two i64 operands"),
+        }
+    }
+
+    fn i64_sub(&self, stack: &mut Vec<Value>) -> Result<Value> {
+        match (stack.pop(), stack.pop()) {
+            (Some(Value::I64(right)), Some(Value::I64(left))) => {
+                Ok(Value::I64(left.wrapping_sub(right)))
+            }
+            _ => bail!("wrong types for i64_sub, expected two i64 operands"),
+        }
+    }
+
+    fn i64_mul(&self, stack: &mut Vec<Value>) -> Result<Value> {
+        match (stack.pop(), stack.pop()) {
+            (Some(Value::I64(right)), Some(Value::I64(left))) => {
+                Ok(Value::I64(left.wrapping_mul(right)))
+            }
+            _ => bail!("wrong types for i64_mul, expected two i64 operands"),
+        }
+    }
+
+    fn f64_add(&self, stack: &mut Vec<Value>) -> Result<Value> {
+        match (stack.pop(), stack.pop()) {
+            (Some(Value::F64(right)), Some(Value::F64(left))) => Ok(Value::F64(left + right)),
+            _ => bail!("wrong types for f64_add, expected two f64 operands"),
+        }
+    }
+
+    fn f64_sub(&self, stack: &mut Vec<Value>) -> Result<Value> {
+        match (stack.pop(), stack.pop()) {
+            (Some(Value::F64(right)), Some(Value::F64(left))) => Ok(Value::F64(left - right)),
+            _ => bail!("wrong types for f64_sub, expected two f64 operands"),
+        }
+    }
+
+    fn f64_mul(&self, stack: &mut Vec<Value>) -> Result<Value> {
+        match (stack.pop(), stack.pop()) {
+            (Some(Value::F64(right)), Some(Value::F64(left))) => Ok(Value::F64(left * right)),
+            _ => bail!("wrong types for f64_mul, expected two f64 operands"),
+        }
+    }
+
+    fn f64_div(&self, stack: &mut Vec<Value>) -> Result<Value> {
+        match (stack.pop(), stack.pop()) {
+            (Some(Value::F64(right)), Some(Value::F64(left))) => Ok(Value::F64(left / right)),
+            _ => bail!("wrong types for f64_div, expected two f64 operands"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(tag = "type", content = "value", rename_all = "lowercase")
+)]
+pub enum Value {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+}
+
+impl Value {
+    /// The zero value of type `ty`, which locals start with. `None` for types
+    /// we have no values for.
+    pub fn zero(ty: &Val) -> Option<Value> {
+        match ty {
+            Val::I32 => Some(Value::I32(0)),
+            Val::I64 => Some(Value::I64(0)),
+            Val::F32 => Some(Value::F32(0.0)),
+            Val::F64 => Some(Value::F64(0.0)),
+            Val::V128 | Val::FuncRef | Val::ExternRef => None,
+        }
+    }
+
+    /// The type of this value.
+    pub fn ty(&self) -> Val {
+        match self {
+            Value::I32(_) => Val::I32,
+            Value::I64(_) => Val::I64,
+            Value::F32(_) => Val::F32,
+            Value::F64(_) => Val::F64,
+        }
+    }
+
+    /// Adds two values of the same type, returning `None` on a type mismatch
+    /// or overflow.
+    pub fn checked_add(self, other: Value) -> Option<Value> {
+        match (self, other) {
+            (Value::I32(left), Value::I32(right)) => left.checked_add(right).map(Value::I32),
+            (Value::I64(left), Value::I64(right)) => left.checked_add(right).map(Value::I64),
+            (Value::F32(left), Value::F32(right)) => Some(Value::F32(left + right)),
+            (Value::F64(left), Value::F64(right)) => Some(Value::F64(left + right)),
+            _ => None,
+        }
+    }
+
+    /// Subtracts two values of the same type, returning `None` on a type
+    /// mismatch or overflow.
+    pub fn checked_sub(self, other: Value) -> Option<Value> {
+        match (self, other) {
+            (Value::I32(left), Value::I32(right)) => left.checked_sub(right).map(Value::I32),
+            (Value::I64(left), Value::I64(right)) => left.checked_sub(right).map(Value::I64),
+            (Value::F32(left), Value::F32(right)) => Some(Value::F32(left - right)),
+            (Value::F64(left), Value::F64(right)) => Some(Value::F64(left - right)),
+            _ => None,
+        }
+    }
+
+    /// Multiplies two values of the same type, returning `None` on a type
+    /// mismatch or overflow.
+    pub fn checked_mul(self, other: Value) -> Option<Value> {
+        match (self, other) {
+            (Value::I32(left), Value::I32(right)) => left.checked_mul(right).map(Value::I32),
+            (Value::I64(left), Value::I64(right)) => left.checked_mul(right).map(Value::I64),
+            (Value::F32(left), Value::F32(right)) => Some(Value::F32(left * right)),
+            (Value::F64(left), Value::F64(right)) => Some(Value::F64(left * right)),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::I32(n) => write!(f, "{n}"),
+            Value::I64(n) => write!(f, "{n}"),
+            Value::F32(n) => write!(f, "{n}"),
+            Value::F64(n) => write!(f, "{n}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use std::time::{Duration, Instant};
+
+    // Counts its param down to 0 with `call_opcode` and returns 42.
+    fn countdown(call_opcode: u8) -> Vec<u8> {
+        #[rustfmt::skip]
+        let code = vec![
+            0x02, 0x40, // block
+            0x20, 0, 0x45, 0x0d, 0, // br_if 0 when the param is 0
+            0x20, 0, 0x41, 1, 0x6b, // param - 1
+            call_opcode, 0, 0x0f, // call 0, return
+            0x0b, 0x41, 42, 0x0b,
+        ];
+        single_func(&[I32], &[I32], &[], code)
+    }
+
+    #[test]
+    fn tail_calls_reuse_the_frame() {
+        let mut store = Store::default();
+        let f = func(&mut store, &countdown(0x12));
+        let depth = MAX_CALL_DEPTH as i32 * 4;
+        assert_eq!(
+            f.call(&mut store, &[Value::I32(depth)]).unwrap(),
+            [Value::I32(42)]
+        );
+
+        let f = func(&mut store, &countdown(0x10));
+        let e = f.call(&mut store, &[Value::I32(depth)]).unwrap_err();
+        assert!(e.to_string().contains("stack exhausted"), "{e}");
+    }
+
+    #[test]
+    fn return_call_indirect_is_rejected() {
+        let code = vec![0x41, 0, 0x13, 0, 0, 0x0b];
+        let bytes = single_func(&[], &[], &[], code);
+        let mut store = Store::default();
+        let module = Module::from_bytes(&store, &bytes).unwrap();
+        let e = Instance::new(&mut store, module, &Imports::new())
+            .map(|_| ())
+            .unwrap_err();
+        assert!(format!("{e:#}").contains("unknown table 0"), "{e:#}");
+    }
+
+    fn trap_kind(result: Result<Vec<Value>, WasmError>) -> TrapKind {
+        match result {
+            Err(WasmError::Trap(trap)) => trap.kind,
+            other => panic!("expected a trap, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn bulk_memory() {
+        let args3 = [0x20, 0, 0x20, 1, 0x20, 2];
+        let bytes = funcs_module(
+            vec![
+                (
+                    "init",
+                    func_type(&[I32; 3], &[]),
+                    [&args3[..], &[0xfc, 8, 0, 0, 0x0b]].concat(),
+                ),
+                (
+                    "init_active",
+                    func_type(&[I32; 3], &[]),
+                    [&args3[..], &[0xfc, 8, 1, 0, 0x0b]].concat(),
+                ),
+                ("drop", func_type(&[], &[]), vec![0xfc, 9, 0, 0x0b]),
+                (
+                    "copy",
+                    func_type(&[I32; 3], &[]),
+                    [&args3[..], &[0xfc, 10, 0, 0, 0x0b]].concat(),
+                ),
+                (
+                    "fill",
+                    func_type(&[I32; 3], &[]),
+                    [&args3[..], &[0xfc, 11, 0, 0x0b]].concat(),
+                ),
+            ],
+            vec![export("mem", MEMORY, 0)],
+            vec![
+                section(5, vec(vec![vec![0x00, 1]])),
+                section(11, vec(vec![passive_data(b"hello"), active_data(0, b"ab")])),
+            ],
+        );
+        let mut store = Store::default();
+        let instance = instantiate(&mut store, &bytes);
+        let exports = &instance.exports;
+        let mem = exports.get_memory("mem").unwrap();
+        let call = |store: &mut Store, name: &str, args: [i32; 3]| {
+            let args = args.map(Value::I32);
+            let args = if name == "drop" { &[][..] } else { &args[..] };
+            exports.get_function(name).unwrap().call(store, args)
+        };
+
+        call(&mut store, "init", [10, 1, 3]).unwrap();
+        assert_eq!(&mem.data(&store)[10..13], b"ell");
+        let result = call(&mut store, "init", [10, 3, 3]);
+        assert_eq!(trap_kind(result), TrapKind::OutOfBounds);
+        let result = call(&mut store, "init", [PAGE_SIZE as i32 - 1, 0, 2]);
+        assert_eq!(trap_kind(result), TrapKind::OutOfBounds);
+
+        // Overlapping ranges are copied as if through a buffer
+        call(&mut store, "copy", [11, 10, 3]).unwrap();
+        assert_eq!(&mem.data(&store)[10..14], b"eell");
+        call(&mut store, "copy", [0, 11, 2]).unwrap();
+        assert_eq!(&mem.data(&store)[..2], b"el");
+        let result = call(&mut store, "copy", [0, -1, 2]);
+        assert_eq!(trap_kind(result), TrapKind::OutOfBounds);
+
+        call(&mut store, "fill", [30, 0x141, 4]).unwrap();
+        assert_eq!(&mem.data(&store)[29..35], b"\0AAAA\0");
+        let result = call(&mut store, "fill", [PAGE_SIZE as i32, 0, 1]);
+        assert_eq!(trap_kind(result), TrapKind::OutOfBounds);
+
+        // Dropped segments, active segments included, are empty
+        call(&mut store, "drop", [0; 3]).unwrap();
+        call(&mut store, "init", [0, 0, 0]).unwrap();
+        let result = call(&mut store, "init", [0, 0, 1]);
+        assert_eq!(trap_kind(result), TrapKind::OutOfBounds);
+        call(&mut store, "init_active", [0, 0, 0]).unwrap();
+        let result = call(&mut store, "init_active", [0, 0, 1]);
+        assert_eq!(trap_kind(result), TrapKind::OutOfBounds);
+    }
+
+    #[test]
+    fn atomic_fence() {
+        let code = vec![0xfe, 3, 0, 0x41, 7, 0x0b];
+        let mut store = Store::default();
+        let f = func(&mut store, &single_func(&[], &[I32], &[], code));
+        assert_eq!(f.call(&mut store, &[]).unwrap(), [Value::I32(7)]);
+    }
+
+    fn mixed_exports(exports: Vec<Vec<u8>>) -> Vec<u8> {
+        funcs_module(
+            vec![
+                ("f", func_type(&[], &[]), vec![0x0b]),
+                ("g", func_type(&[], &[I32]), vec![0x41, 1, 0x0b]),
+            ],
+            exports,
+            vec![
+                section(5, vec(vec![vec![0x00, 1]])),
+                section(6, vec(vec![vec![I32, 1, 0x41, 5, 0x0b]])),
+            ],
+        )
+    }
+
+    #[test]
+    fn exports_by_kind() {
+        let bytes = mixed_exports(vec![export("counter", GLOBAL, 0), export("mem", MEMORY, 0)]);
+        let mut store = Store::default();
+        let exports = instantiate(&mut store, &bytes).exports;
+
+        let funcs: Vec<_> = exports
+            .functions()
+            .map(|(name, f)| (name, f.unwrap().idx))
+            .collect();
+        assert_eq!(funcs, [("f", 0), ("g", 1)]);
+        let globals: Vec<_> = exports
+            .globals()
+            .map(|(name, g)| (name, g.get(&store)))
+            .collect();
+        assert_eq!(globals, [("counter", Value::I32(5))]);
+        let memories: Vec<_> = exports
+            .memories()
+            .map(|(name, m)| (name, m.size(&store)))
+            .collect();
+        assert_eq!(memories, [("mem", PAGE_SIZE)]);
+    }
+
+    #[test]
+    fn unknown_exports_are_rejected() {
+        for (export, message) in [
+            (
+                export("g", GLOBAL, 1),
+                "export g refers to unknown Global 1",
+            ),
+            (
+                export("m", MEMORY, 1),
+                "export m refers to unknown Memory 1",
+            ),
+            (export("f", FUNC, 2), "export f refers to unknown Func 2"),
+        ] {
+            let bytes = mixed_exports(vec![export]);
+            let mut store = Store::default();
+            let module = Module::from_bytes(&store, &bytes).unwrap();
+            assert_eq!(module.validate().unwrap_err().to_string(), message);
+            let e = Instance::new(&mut store, module, &Imports::new())
+                .map(|_| ())
+                .unwrap_err();
+            assert_eq!(e.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn load_through_pointer_argument() {
+        let code = vec![0x20, 0, 0x28, 2, 0, 0x41, 1, 0x6a, 0x0b];
+        let bytes = funcs_module(
+            vec![("f", func_type(&[I32], &[I32]), code)],
+            vec![],
+            vec![
+                section(5, vec(vec![vec![0x00, 1]])),
+                section(11, vec(vec![active_data(16, &41i32.to_le_bytes())])),
+            ],
+        );
+        let mut store = Store::default();
+        let f = func(&mut store, &bytes);
+        assert_eq!(
+            f.call(&mut store, &[Value::I32(16)]).unwrap(),
+            [Value::I32(42)]
+        );
+        assert_eq!(
+            f.call(&mut store, &[Value::I32(0)]).unwrap(),
+            [Value::I32(1)]
+        );
+    }
+
+    #[test]
+    fn call_with_two_results() {
+        let bytes = funcs_module(
+            vec![
+                (
+                    "pair",
+                    func_type(&[], &[I32, I32]),
+                    vec![0x41, 7, 0x41, 3, 0x0b],
+                ),
+                ("sub", func_type(&[], &[I32]), vec![0x10, 0, 0x6b, 0x0b]),
+                ("forward", func_type(&[], &[I32, I32]), vec![0x10, 0, 0x0b]),
+            ],
+            vec![],
+            vec![],
+        );
+        let mut store = Store::default();
+        let exports = instantiate(&mut store, &bytes).exports;
+        let call = |store: &mut Store, name| exports.get_function(name).unwrap().call(store, &[]);
+        assert_eq!(call(&mut store, "sub").unwrap(), [Value::I32(4)]);
+        assert_eq!(
+            call(&mut store, "forward").unwrap(),
+            [Value::I32(7), Value::I32(3)]
+        );
+    }
+
+    #[test]
+    fn max_memory_pages() {
+        // memory.grow by the param, returning the old size
+        let grow = vec![0x20, 0, 0x40, 0, 0x0b];
+        let with_memory = |limits: Vec<u8>| {
+            funcs_module(
+                vec![("grow", func_type(&[I32], &[I32]), grow.clone())],
+                vec![],
+                vec![section(5, vec(vec![limits]))],
+            )
+        };
+        let mut store = Store::new(Config {
+            max_memory_pages: Some(4),
+            ..Default::default()
+        });
+
+        let instance = instantiate(&mut store, &with_memory(vec![0x01, 1, 10]));
+        assert_eq!(instance.max_memory_pages(), Some(10));
+        let grow = instance.exports.get_function("grow").unwrap();
+        assert_eq!(
+            grow.call(&mut store, &[Value::I32(9)]).unwrap(),
+            [Value::I32(1)]
+        );
+
+        let instance = instantiate(&mut store, &with_memory(vec![0x00, 1]));
+        assert_eq!(instance.max_memory_pages(), Some(4));
+        let grow = instance.exports.get_function("grow").unwrap();
+        assert_eq!(
+            grow.call(&mut store, &[Value::I32(4)]).unwrap(),
+            [Value::I32(-1)]
+        );
+        assert_eq!(
+            grow.call(&mut store, &[Value::I32(3)]).unwrap(),
+            [Value::I32(1)]
+        );
+
+        let instance = instantiate(&mut Store::default(), &with_memory(vec![0x00, 1]));
+        assert_eq!(instance.max_memory_pages(), Some(MAX_PAGES));
+        let bytes = single_func(&[], &[], &[], vec![0x0b]);
+        assert_eq!(instantiate(&mut store, &bytes).max_memory_pages(), None);
+    }
+
+    #[test]
+    fn strict_alignment() {
+        // i32.load declaring a 4 bytes alignment, with an offset of 2
+        let code = vec![0x20, 0, 0x28, 2, 2, 0x0b];
+        let bytes = funcs_module(
+            vec![("f", func_type(&[I32], &[I32]), code)],
+            vec![],
+            vec![section(5, vec(vec![vec![0x00, 1]]))],
+        );
+        let mut store = Store::default();
+        let f = func(&mut store, &bytes);
+        assert!(f.call(&mut store, &[Value::I32(0)]).is_ok());
+
+        let mut store = Store::new(Config {
+            strict_alignment: true,
+            ..Default::default()
+        });
+        let f = func(&mut store, &bytes);
+        assert!(f.call(&mut store, &[Value::I32(2)]).is_ok());
+        let result = f.call(&mut store, &[Value::I32(0)]);
+        assert_eq!(trap_kind(result), TrapKind::Misaligned);
+
+        // Alignments larger than the access are invalid
+        let code = vec![0x41, 0, 0x2d, 1, 0, 0x0b];
+        let bytes = funcs_module(
+            vec![("f", func_type(&[], &[I32]), code)],
+            vec![],
+            vec![section(5, vec(vec![vec![0x00, 1]]))],
+        );
+        let module = Module::from_bytes(&store, &bytes).unwrap();
+        let e = module.validate().unwrap_err();
+        assert!(
+            format!("{e:#}").contains("alignment must not be larger than 1 bytes"),
+            "{e:#}"
+        );
+    }
+
+    // An instance whose memory, exported as "mem", holds `data` at 0.
+    fn memory_with(store: &mut Store, data: &[u8]) -> Memory {
+        let bytes = module(vec![
+            section(5, vec(vec![vec![0x00, 1]])),
+            section(7, vec(vec![export("mem", MEMORY, 0)])),
+            section(11, vec(vec![active_data(0, data)])),
+        ]);
+        instantiate(store, &bytes)
+            .exports
+            .get_memory("mem")
+            .unwrap()
+    }
+
+    #[test]
+    fn read_strings() {
+        let mut store = Store::default();
+        let mem = memory_with(&mut store, b"hello\0\xff\xfe\0");
+
+        assert_eq!(mem.read_string(&store, 1, 3).unwrap(), "ell");
+        assert_eq!(mem.read_string(&store, 0, 0).unwrap(), "");
+        assert_eq!(mem.read_cstr(&store, 0).unwrap(), "hello");
+        assert_eq!(mem.read_cstr(&store, 5).unwrap(), "");
+
+        let e = mem
+            .read_string(&store, PAGE_SIZE as u32 - 2, 3)
+            .unwrap_err();
+        assert_eq!(e.downcast_ref(), Some(&TrapKind::OutOfBounds));
+        let e = mem.read_string(&store, u32::MAX, 2).unwrap_err();
+        assert_eq!(e.downcast_ref(), Some(&TrapKind::OutOfBounds));
+        let e = mem.read_cstr(&store, PAGE_SIZE as u32 + 1).unwrap_err();
+        assert_eq!(e.downcast_ref(), Some(&TrapKind::OutOfBounds));
+        // Nothing but zeroes are after the data, up to the end
+        mem.write(&mut store, PAGE_SIZE - 1, b"x").unwrap();
+        let e = mem.read_cstr(&store, PAGE_SIZE as u32 - 1).unwrap_err();
+        assert_eq!(e.downcast_ref(), Some(&TrapKind::OutOfBounds));
+
+        let e = mem.read_string(&store, 6, 2).unwrap_err();
+        assert_eq!(e.to_string(), "string at 6 isn't valid UTF-8");
+        let e = mem.read_cstr(&store, 6).unwrap_err();
+        assert_eq!(e.to_string(), "string at 6 isn't valid UTF-8");
+    }
+
+    #[test]
+    fn numeric_slices() {
+        let mut store = Store::default();
+        let mem = memory_with(&mut store, &[]);
+
+        let values = vec![1, -2, i32::MAX, i32::MIN];
+        mem.write_i32_slice(&mut store, 8, &values).unwrap();
+        assert_eq!(mem.read_i32_slice(&store, 8, 4).unwrap(), values);
+        assert_eq!(&mem.data(&store)[12..16], (-2i32).to_le_bytes());
+        assert_eq!(
+            mem.read_i32_slice(&store, 12, 0).unwrap(),
+            Vec::<i32>::new()
+        );
+
+        let values = vec![0.5, -1e300, f64::INFINITY];
+        mem.write_f64_slice(&mut store, 3, &values).unwrap();
+        assert_eq!(mem.read_f64_slice(&store, 3, 3).unwrap(), values);
+
+        let end = PAGE_SIZE as u32;
+        let e = mem.read_i32_slice(&store, end - 4, 2).unwrap_err();
+        assert_eq!(e.downcast_ref(), Some(&TrapKind::OutOfBounds));
+        let e = mem.read_f64_slice(&store, 0, usize::MAX).unwrap_err();
+        assert_eq!(e.downcast_ref(), Some(&TrapKind::OutOfBounds));
+        let e = mem
+            .write_i32_slice(&mut store, end - 4, &[1, 2])
+            .unwrap_err();
+        assert_eq!(e.downcast_ref(), Some(&TrapKind::OutOfBounds));
+        let e = mem.write_f64_slice(&mut store, end, &[1.0]).unwrap_err();
+        assert_eq!(e.downcast_ref(), Some(&TrapKind::OutOfBounds));
+    }
+
+    #[test]
+    fn passive_data_is_copied_by_memory_init() {
+        let code = vec![0x41, 4, 0x41, 0, 0x41, 3, 0xfc, 8, 0, 0, 0x0b];
+        let bytes = funcs_module(
+            vec![("init", func_type(&[], &[]), code)],
+            vec![export("mem", MEMORY, 0)],
+            vec![
+                section(5, vec(vec![vec![0x00, 1]])),
+                section(11, vec(vec![passive_data(b"xyz"), active_data(0, b"ab")])),
+            ],
+        );
+        let mut store = Store::default();
+        let module = Module::from_bytes(&store, &bytes).unwrap();
+        assert_eq!(module.data[0].mode, DataMode::Passive);
+        assert!(matches!(
+            module.data[1].mode,
+            DataMode::Active { memory: 0, .. }
+        ));
+
+        let exports = Instance::new(&mut store, module, &Imports::new())
+            .unwrap()
+            .exports;
+        let mem = exports.get_memory("mem").unwrap();
+        assert_eq!(&mem.data(&store)[..8], b"ab\0\0\0\0\0\0");
+        assert!(mem.data(&store)[2..].iter().all(|b| *b == 0));
+
+        let init = exports.get_function("init").unwrap();
+        init.call(&mut store, &[]).unwrap();
+        assert_eq!(&mem.data(&store)[..8], b"ab\0\0xyz\0");
+    }
+
+    #[test]
+    fn grown_pages_are_zeroed() {
+        for preallocate_max_memory in [false, true] {
+            let bytes = funcs_module(
+                vec![
+                    (
+                        "grow",
+                        func_type(&[I32], &[I32]),
+                        vec![0x20, 0, 0x40, 0, 0x0b],
+                    ),
+                    (
+                        "load",
+                        func_type(&[I32], &[I32]),
+                        vec![0x20, 0, 0x2d, 0, 0, 0x0b],
+                    ),
+                ],
+                vec![],
+                vec![
+                    section(5, vec(vec![vec![0x01, 1, 3]])),
+                    section(11, vec(vec![active_data(PAGE_SIZE as i32 - 2, &[0xff; 2])])),
+                ],
+            );
+            let mut store = Store::new(Config {
+                preallocate_max_memory,
+                ..Default::default()
+            });
+            let exports = instantiate(&mut store, &bytes).exports;
+            let grow = exports.get_function("grow").unwrap();
+            let load = exports.get_function("load").unwrap();
+            let load = |store: &mut Store, offset: usize| {
+                load.call(store, &[Value::I32(offset as i32)]).unwrap()
+            };
+
+            assert_eq!(load(&mut store, 0), [Value::I32(0)]);
+            assert_eq!(load(&mut store, PAGE_SIZE - 3), [Value::I32(0)]);
+            assert_eq!(
+                grow.call(&mut store, &[Value::I32(2)]).unwrap(),
+                [Value::I32(1)]
+            );
+            assert_eq!(load(&mut store, PAGE_SIZE - 1), [Value::I32(0xff)]);
+            for offset in [
+                PAGE_SIZE,
+                PAGE_SIZE + 1,
+                2 * PAGE_SIZE - 1,
+                2 * PAGE_SIZE,
+                3 * PAGE_SIZE - 1,
+            ] {
+                assert_eq!(load(&mut store, offset), [Value::I32(0)], "offset {offset}");
+            }
+        }
+    }
+
+    #[test]
+    fn lenient_linking() {
+        // "f" calls the undefined import, "g" doesn't
+        let bytes = module(vec![
+            section(1, vec(vec![func_type(&[], &[I32])])),
+            section(2, vec(vec![import("env", "missing", vec![FUNC, 0])])),
+            section(3, vec(vec![leb(0), leb(0)])),
+            section(7, vec(vec![export("f", FUNC, 1), export("g", FUNC, 2)])),
+            section(
+                10,
+                vec(vec![
+                    body(&[], vec![0x10, 0, 0x0b]),
+                    body(&[], vec![0x41, 7, 0x0b]),
+                ]),
+            ),
+        ]);
+
+        let mut store = Store::default();
+        let module = Module::from_bytes(&store, &bytes).unwrap();
+        let e = Instance::new(&mut store, module, &Imports::new())
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "unresolved import env.missing");
+
+        let mut store = Store::new(Config {
+            lenient_linking: true,
+            ..Default::default()
+        });
+        let exports = instantiate(&mut store, &bytes).exports;
+        let names: Vec<_> = exports.functions().map(|(name, _)| name).collect();
+        assert_eq!(names, ["f", "g"]);
+        let g = exports.get_function("g").unwrap();
+        assert_eq!(g.call(&mut store, &[]).unwrap(), [Value::I32(7)]);
+        let f = exports.get_function("f").unwrap();
+        assert_eq!(
+            trap_kind(f.call(&mut store, &[])),
+            TrapKind::UndefinedImport {
+                module: "env".to_string(),
+                name: "missing".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn call_named() {
+        // Local names subsection naming the params of function 0 (a, b)
+        let locals = vec(vec![
+            [leb(0), name("a")].concat(),
+            [leb(1), name("b")].concat(),
+        ]);
+        let subsection = vec(vec![[leb(0), locals].concat()]);
+        let contents = [
+            name("name"),
+            vec![2],
+            leb(subsection.len() as u64),
+            subsection,
+        ]
+        .concat();
+        let bytes = funcs_module(
+            vec![(
+                "f",
+                func_type(&[I32, I32], &[I32]),
+                vec![0x20, 0, 0x20, 1, 0x6b, 0x0b],
+            )],
+            vec![],
+            vec![section(0, contents)],
+        );
+        let mut store = Store::default();
+        let f = func(&mut store, &bytes);
+
+        let positional = f.call(&mut store, &[Value::I32(7), Value::I32(2)]);
+        let args = HashMap::from([
+            ("b".to_string(), Value::I32(2)),
+            ("a".to_string(), Value::I32(7)),
+        ]);
+        assert_eq!(
+            f.call_named(&mut store, &args).unwrap(),
+            positional.unwrap()
+        );
+        assert_eq!(f.call_named(&mut store, &args).unwrap(), [Value::I32(5)]);
+
+        let missing = HashMap::from([("a".to_string(), Value::I32(7))]);
+        let e = f.call_named(&mut store, &missing).unwrap_err();
+        assert_eq!(e.to_string(), "missing argument b");
+        let mut unknown = args.clone();
+        unknown.insert("c".to_string(), Value::I32(0));
+        let e = f.call_named(&mut store, &unknown).unwrap_err();
+        assert_eq!(e.to_string(), "unknown param c");
+    }
+
+    #[test]
+    fn empty_segments() {
+        let with_data = |offset: usize| {
+            module(vec![
+                // A table of funcrefs and an empty element segment into it
+                section(4, vec(vec![vec![0x70, 0x00, 0]])),
+                section(5, vec(vec![vec![0x00, 1]])),
+                section(9, vec(vec![vec![0x00, 0x41, 0, 0x0b, 0]])),
+                section(
+                    11,
+                    vec(vec![active_data(0, &[]), active_data(offset as i32, &[])]),
+                ),
+            ])
+        };
+
+        let mut store = Store::default();
+        instantiate(&mut store, &with_data(PAGE_SIZE));
+        let module = Module::from_bytes(&store, &with_data(PAGE_SIZE + 1)).unwrap();
+        let e = Instance::new(&mut store, module, &Imports::new())
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            "initialize data segment 1: out of bounds memory access"
+        );
+    }
+
+    #[test]
+    fn coredump() {
+        let ty = || func_type(&[], &[I32]);
+        let bytes = funcs_module(
+            vec![
+                ("f", ty(), vec![0x10, 1, 0x0b]),
+                ("g", ty(), vec![0x10, 2, 0x0b]),
+                ("h", ty(), vec![0x41, 1, 0x41, 0, 0x6d, 0x0b]),
+            ],
+            vec![],
+            vec![
+                section(5, vec(vec![vec![0x00, 1]])),
+                section(6, vec(vec![vec![I32, 1, 0x41, 5, 0x0b]])),
+                section(11, vec(vec![active_data(0, &[1, 2, 3])])),
+            ],
+        );
+        let mut store = Store::default();
+        store.enable_coredumps();
+        let f = func(&mut store, &bytes);
+        let Err(WasmError::Trap(trap)) = f.call(&mut store, &[]) else {
+            panic!("expected a trap");
+        };
+        assert_eq!(trap.kind, TrapKind::DivByZero);
+
+        let dump = trap.coredump.unwrap();
+        let funcs: Vec<_> = dump.frames.iter().map(|frame| frame.func).collect();
+        assert_eq!(funcs, [2, 1, 0]);
+        assert_eq!(dump.frames[0].offset, 2);
+        assert_eq!(dump.globals, [Value::I32(5)]);
+        assert_eq!(dump.memories.len(), 1);
+        assert_eq!(dump.memories[0].len(), PAGE_SIZE);
+        assert_eq!(dump.memories[0][..4], [1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn value_arithmetic() {
+        assert_eq!(
+            Value::I32(2).checked_add(Value::I32(3)),
+            Some(Value::I32(5))
+        );
+        assert_eq!(
+            Value::I64(2).checked_sub(Value::I64(3)),
+            Some(Value::I64(-1))
+        );
+        assert_eq!(
+            Value::F64(1.5).checked_mul(Value::F64(2.0)),
+            Some(Value::F64(3.0))
+        );
+        assert_eq!(Value::I32(i32::MAX).checked_add(Value::I32(1)), None);
+        assert_eq!(Value::I32(2).checked_add(Value::I64(3)), None);
+    }
+
+    #[test]
+    fn trace_output() {
+        #[derive(Clone, Default)]
+        struct Buffer(Rc<std::cell::RefCell<Vec<u8>>>);
+        impl Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let bytes = single_func(&[I32, I32], &[I32], &[], vec![0x20, 0, 0x20, 1, 0x6a, 0x0b]);
+        let mut store = Store::default();
+        let buffer = Buffer::default();
+        store.set_trace_output(buffer.clone());
+        let f = func(&mut store, &bytes);
+        f.call(&mut store, &[Value::I32(1), Value::I32(2)]).unwrap();
+        let trace = String::from_utf8(buffer.0.take()).unwrap();
+        assert_eq!(trace, "0 0 0x20\n0 1 0x20\n0 2 0x6a\n0 3 0x0b\n");
+    }
+
+    #[test]
+    fn globals_persist_across_calls() {
+        let bytes = funcs_module(
+            vec![
+                (
+                    "increment",
+                    func_type(&[], &[]),
+                    vec![0x23, 0, 0x41, 1, 0x6a, 0x24, 0, 0x0b],
+                ),
+                ("get_counter", func_type(&[], &[I32]), vec![0x23, 0, 0x0b]),
+            ],
+            vec![],
+            vec![section(6, vec(vec![vec![I32, 1, 0x41, 0, 0x0b]]))],
+        );
+        let mut store = Store::default();
+        let module = Module::from_bytes(&store, &bytes)
+            .unwrap()
+            .compile()
+            .unwrap();
+        let first = Instance::from_compiled(&mut store, &module, &Imports::new()).unwrap();
+        let second = Instance::from_compiled(&mut store, &module, &Imports::new()).unwrap();
+
+        let increment = first.exports.get_function("increment").unwrap();
+        for _ in 0..3 {
+            increment.call(&mut store, &[]).unwrap();
+        }
+        let get_counter = |instance: &Instance, store: &mut Store| {
+            let f = instance.exports.get_function("get_counter").unwrap();
+            f.call(store, &[]).unwrap()
+        };
+        assert_eq!(get_counter(&first, &mut store), [Value::I32(3)]);
+        // Every instance has its own globals
+        assert_eq!(get_counter(&second, &mut store), [Value::I32(0)]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_values() {
+        let values = vec![Value::I32(42), Value::F64(1.5)];
+        assert_eq!(
+            serde_json::to_string(&values).unwrap(),
+            r#"[{"type":"i32","value":42},{"type":"f64","value":1.5}]"#
+        );
+    }
+
+    #[test]
+    fn demote_and_promote() {
+        let bytes = funcs_module(
+            vec![
+                (
+                    "demote",
+                    func_type(&[F64], &[F32]),
+                    vec![0x20, 0, 0xb6, 0x0b],
+                ),
+                (
+                    "promote",
+                    func_type(&[F32], &[F64]),
+                    vec![0x20, 0, 0xbb, 0x0b],
+                ),
+            ],
+            vec![],
+            vec![],
+        );
+        let mut store = Store::default();
+        let exports = instantiate(&mut store, &bytes).exports;
+        let demote = exports.get_function("demote").unwrap();
+        let promote = exports.get_function("promote").unwrap();
+
+        let demoted = demote.call(&mut store, &[Value::F64(0.1)]).unwrap();
+        assert_eq!(demoted, [Value::F32(0.1)]);
+        // The precision lost by demoting isn't recovered
+        let promoted = promote.call(&mut store, &demoted).unwrap();
+        assert_eq!(promoted, [Value::F64(0.1f32 as f64)]);
+        assert_ne!(promoted, [Value::F64(0.1)]);
+
+        let infinity = demote
+            .call(&mut store, &[Value::F64(f64::INFINITY)])
+            .unwrap();
+        assert_eq!(infinity, [Value::F32(f32::INFINITY)]);
+        let nan = demote.call(&mut store, &[Value::F64(f64::NAN)]).unwrap();
+        assert!(matches!(nan[..], [Value::F32(n)] if n.is_nan()));
+    }
+
+    #[test]
+    fn reinterpret() {
+        let bytes = funcs_module(
+            vec![
+                (
+                    "i32_bits",
+                    func_type(&[F32], &[I32]),
+                    vec![0x20, 0, 0xbc, 0x0b],
+                ),
+                (
+                    "from_i32_bits",
+                    func_type(&[I32], &[F32]),
+                    vec![0x20, 0, 0xbe, 0x0b],
+                ),
+                (
+                    "i64_bits",
+                    func_type(&[F64], &[I64]),
+                    vec![0x20, 0, 0xbd, 0x0b],
+                ),
+                (
+                    "from_i64_bits",
+                    func_type(&[I64], &[F64]),
+                    vec![0x20, 0, 0xbf, 0x0b],
+                ),
+            ],
+            vec![],
+            vec![],
+        );
+        let mut store = Store::default();
+        let exports = instantiate(&mut store, &bytes).exports;
+        let mut call = |name: &str, arg: Value| {
+            let f = exports.get_function(name).unwrap();
+            f.call(&mut store, &[arg]).unwrap()[0]
+        };
+
+        assert_eq!(call("i32_bits", Value::F32(1.0)), Value::I32(0x3f80_0000));
+        // NaN payloads are kept
+        let bits = call("i32_bits", Value::F32(f32::from_bits(0x7fc0_1234)));
+        let Value::F32(nan) = call("from_i32_bits", bits) else {
+            panic!("expected an f32");
+        };
+        assert_eq!(nan.to_bits(), 0x7fc0_1234);
+
+        let bits = call("i64_bits", Value::F64(-2.5));
+        assert_eq!(bits, Value::I64((-2.5f64).to_bits() as i64));
+        assert_eq!(call("from_i64_bits", bits), Value::F64(-2.5));
+    }
+
+    #[test]
+    fn max_value_stack() {
+        // Pushes three values before adding them up
+        let code = vec![0x41, 1, 0x41, 2, 0x41, 3, 0x6a, 0x6a, 0x0b];
+        let bytes = single_func(&[], &[I32], &[], code);
+        let mut store = Store::new(Config {
+            max_value_stack: Some(3),
+            ..Default::default()
+        });
+        let f = func(&mut store, &bytes);
+        assert_eq!(f.call(&mut store, &[]).unwrap(), [Value::I32(6)]);
+
+        let mut store = Store::new(Config {
+            max_value_stack: Some(2),
+            ..Default::default()
+        });
+        let f = func(&mut store, &bytes);
+        let e = f.call(&mut store, &[]).unwrap_err();
+        assert!(e.to_string().contains("more than 2 values"), "{e}");
+        assert_eq!(trap_kind(Err(e)), TrapKind::StackExhausted);
+    }
+
+    #[test]
+    fn start_function() {
+        // The start function sets the global read by "get"
+        let bytes = module(vec![
+            section(1, vec(vec![func_type(&[], &[]), func_type(&[], &[I32])])),
+            section(3, vec(vec![leb(0), leb(1)])),
+            section(6, vec(vec![vec![I32, 1, 0x41, 0, 0x0b]])),
+            section(7, vec(vec![export("get", FUNC, 1)])),
+            section(8, leb(0)),
+            section(
+                10,
+                vec(vec![
+                    body(&[], vec![0x41, 42, 0x24, 0, 0x0b]),
+                    body(&[], vec![0x23, 0, 0x0b]),
+                ]),
+            ),
+        ]);
+        let mut store = Store::default();
+        let instance = instantiate(&mut store, &bytes);
+        assert!(instance.ran_start());
+        let get = instance.exports.get_function("get").unwrap();
+        assert_eq!(get.call(&mut store, &[]).unwrap(), [Value::I32(42)]);
+
+        let mut store = Store::new(Config {
+            max_value_stack: Some(0),
+            ..Default::default()
+        });
+        let module = Module::from_bytes(&store, &bytes).unwrap();
+        let e = Instance::new(&mut store, module, &Imports::new())
+            .err()
+            .unwrap();
+        assert!(
+            format!("{e:#}").starts_with("trap in start function 0: stack exhausted"),
+            "{e:#}"
+        );
+    }
+
+    #[test]
+    fn nested_block_end() {
+        // block (block (i32.const 1, local.set 0) end) end, then local.get 0 + 2
+        #[rustfmt::skip]
+        let code = vec![
+            0x02, 0x40,
+                0x02, 0x40,
+                    0x41, 1, 0x21, 0,
+                0x0b,
+            0x0b,
+            0x20, 0, 0x41, 2, 0x6a,
+            0x0b,
+        ];
+        let bytes = single_func(&[], &[I32], &[(1, I32)], code);
+        let mut store = Store::default();
+        let f = func(&mut store, &bytes);
+        assert_eq!(f.call(&mut store, &[]).unwrap(), [Value::I32(3)]);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn operand_types_are_checked_in_debug_builds() {
+        let code = vec![0x41, 1, 0x41, 2, 0x6a, 0x0b];
+        let bytes = single_func(&[], &[I32], &[], code);
+        let mut store = Store::default();
+        let f = func(&mut store, &bytes);
+        // Validation can't be fooled, so i64s are pushed behind its back
+        store.intercept(0x41, |_, stack, _| {
+            stack.push(Value::I64(1));
+            Ok(true)
+        });
+        let e = f.call(&mut store, &[]).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("I32Add expects operands [I32, I32], got [I64(1), I64(1)]"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn call_sibling() {
+        let bytes = funcs_module(
+            vec![
+                (
+                    "add",
+                    func_type(&[I32, I32], &[I32]),
+                    vec![0x20, 0, 0x20, 1, 0x6a, 0x0b],
+                ),
+                (
+                    "double",
+                    func_type(&[I32], &[I32]),
+                    vec![0x20, 0, 0x20, 0, 0x10, 0, 0x0b],
+                ),
+                ("rec", func_type(&[], &[]), vec![0x10, 2, 0x0b]),
+            ],
+            vec![],
+            vec![],
+        );
+        let mut store = Store::default();
+        let exports = instantiate(&mut store, &bytes).exports;
+        let double = exports.get_function("double").unwrap();
+        assert_eq!(
+            double.call(&mut store, &[Value::I32(21)]).unwrap(),
+            [Value::I32(42)]
+        );
+
+        let rec = exports.get_function("rec").unwrap();
+        let e = rec.call(&mut store, &[]).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("stack exhausted: more than 512 nested calls"),
+            "{e}"
+        );
+        assert_eq!(store.call_depth, 0);
+    }
+
+    #[test]
+    fn div_u() {
+        // The decoder reads i32.div_u from 0x80
+        let code = vec![0x20, 0, 0x20, 1, 0x80, 0x0b];
+        let bytes = single_func(&[I32, I32], &[I32], &[], code);
+        let mut store = Store::default();
+        let f = func(&mut store, &bytes);
+        let mut div = |a, b| f.call(&mut store, &[Value::I32(a), Value::I32(b)]);
+        assert_eq!(div(7, 2).unwrap(), [Value::I32(3)]);
+        // The operands are unsigned
+        assert_eq!(div(-1, 2).unwrap(), [Value::I32(i32::MAX)]);
+        assert_eq!(trap_kind(div(7, 0)), TrapKind::DivByZero);
+    }
+
+    #[test]
+    fn multi_value_results() {
+        let bytes = funcs_module(
+            vec![
+                ("none", func_type(&[], &[]), vec![0x0b]),
+                (
+                    "two",
+                    func_type(&[I32], &[I32, I32]),
+                    vec![0x20, 0, 0x41, 2, 0x0b],
+                ),
+                (
+                    "calls",
+                    func_type(&[], &[I32]),
+                    vec![0x41, 3, 0x10, 1, 0x6a, 0x0b],
+                ),
+            ],
+            vec![],
+            vec![],
+        );
+        let mut store = Store::default();
+        let exports = instantiate(&mut store, &bytes).exports;
+        let mut call = |name: &str, args: &[Value]| {
+            let f = exports.get_function(name).unwrap();
+            f.call(&mut store, args).unwrap()
+        };
+        assert_eq!(call("none", &[]), []);
+        assert_eq!(
+            call("two", &[Value::I32(1)]),
+            [Value::I32(1), Value::I32(2)]
+        );
+        // Both results are left on the caller's stack, 3 + 2
+        assert_eq!(call("calls", &[]), [Value::I32(5)]);
+    }
+
+    #[test]
+    fn i64_arithmetic() {
+        // (a - b) * 3 + 10
+        let code = vec![0x20, 0, 0x20, 1, 0x7d, 0x42, 3, 0x7e, 0x42, 10, 0x7c, 0x0b];
+        let bytes = single_func(&[I64, I64], &[I64], &[], code);
+        let mut store = Store::default();
+        let f = func(&mut store, &bytes);
+        let results = f
+            .call(&mut store, &[Value::I64(10), Value::I64(3)])
+            .unwrap();
+        assert_eq!(results, [Value::I64(31)]);
+        assert_eq!(results[0].to_string(), "31");
+
+        let e = f
+            .call(&mut store, &[Value::I32(1), Value::I64(1)])
+            .unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("expects argument 0 of type I64, got I32(1)"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn f64_arithmetic() {
+        let f64_const = |x: f64| [vec![0x44], x.to_le_bytes().to_vec()].concat();
+        let mul = [f64_const(1.5), f64_const(4.0), vec![0xa2, 0x0b]].concat();
+        // a / b, a - b, a + b
+        #[rustfmt::skip]
+        let ops = vec![
+            0x20, 0, 0x20, 1, 0xa3,
+            0x20, 0, 0x20, 1, 0xa1,
+            0x20, 0, 0x20, 1, 0xa0,
+            0x0b,
+        ];
+        let bytes = funcs_module(
+            vec![
+                ("mul", func_type(&[], &[F64]), mul),
+                ("ops", func_type(&[F64, F64], &[F64, F64, F64]), ops),
+            ],
+            vec![],
+            vec![],
+        );
+        let mut store = Store::default();
+        let exports = instantiate(&mut store, &bytes).exports;
+        let mul = exports.get_function("mul").unwrap();
+        assert_eq!(mul.call(&mut store, &[]).unwrap(), [Value::F64(6.0)]);
+
+        // Dividing by zero doesn't trap
+        let ops = exports.get_function("ops").unwrap();
+        let results = ops.call(&mut store, &[Value::F64(1.0), Value::F64(0.0)]);
+        let expected = [Value::F64(f64::INFINITY), Value::F64(1.0), Value::F64(1.0)];
+        assert_eq!(results.unwrap(), expected);
+        let results = ops.call(&mut store, &[Value::F64(0.0), Value::F64(0.0)]);
+        assert!(matches!(results.unwrap()[0], Value::F64(x) if x.is_nan()));
+    }
+
+    #[test]
+    fn local_set_and_tee() {
+        // local 1 = p0 * 3, local.tee 1 (local 1 + 1), local 1, local 2
+        #[rustfmt::skip]
+        let code = vec![
+            0x20, 0, 0x41, 3, 0x6c, 0x21, 1,
+            0x20, 1, 0x41, 1, 0x6a, 0x22, 1,
+            0x20, 1,
+            0x20, 2,
+            0x0b,
+        ];
+        let bytes = single_func(&[I32], &[I32, I32, I64], &[(1, I32), (1, I64)], code);
+        let mut store = Store::default();
+        let f = func(&mut store, &bytes);
+        let results = f.call(&mut store, &[Value::I32(4)]).unwrap();
+        assert_eq!(results, [Value::I32(13), Value::I32(13), Value::I64(0)]);
+    }
+
+    #[test]
+    fn check_slot_types() {
+        let bytes = module(vec![
+            section(1, vec(vec![func_type(&[], &[])])),
+            section(3, vec(vec![leb(0), leb(0)])),
+            section(6, vec(vec![vec![I32, 1, 0x41, 7, 0x0b]])),
+            section(
+                7,
+                vec(vec![export("local", FUNC, 0), export("global", FUNC, 1)]),
+            ),
+            section(
+                10,
+                vec(vec![
+                    body(&[(1, I32)], vec![0x41, 1, 0x21, 0, 0x0b]),
+                    body(&[], vec![0x41, 1, 0x24, 0, 0x0b]),
+                ]),
+            ),
+        ]);
+        let run = |check_slot_types: bool, name: &str| {
+            let mut store = Store::new(Config {
+                check_slot_types,
+                ..Default::default()
+            });
+            let f = instantiate(&mut store, &bytes)
+                .exports
+                .get_function(name)
+                .unwrap();
+            // Validation can't be fooled, so an i64 is pushed behind its back
+            store.intercept(0x41, |_, stack, _| {
+                stack.push(Value::I64(1));
+                Ok(true)
+            });
+            f.call(&mut store, &[])
+        };
+
+        assert!(run(false, "local").is_ok());
+        let e = run(true, "local").unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("store of I64(1) to local 0 of type I32"),
+            "{e}"
+        );
+        let e = run(true, "global").unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("global.set of I64(1) to global 0 of type I32"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn declared_locals_start_at_zero() {
+        // local 2 = local 1 + p0, then local 2 and local 1
+        let code = vec![0x20, 1, 0x20, 0, 0x6a, 0x21, 2, 0x20, 2, 0x20, 1, 0x0b];
+        let bytes = single_func(&[I32], &[I32, I32], &[(2, I32)], code);
+        let mut store = Store::default();
+        let f = func(&mut store, &bytes);
+        let results = f.call(&mut store, &[Value::I32(4)]).unwrap();
+        assert_eq!(results, [Value::I32(4), Value::I32(0)]);
+
+        let e = f.call(&mut store, &[]).unwrap_err();
+        assert!(e.to_string().contains("expects 1 arguments, got 0"), "{e}");
+    }
+
+    #[test]
+    fn memory_section() {
+        let bytes = module(vec![
+            section(5, vec(vec![vec![0x00, 1]])),
+            section(7, vec(vec![export("mem", MEMORY, 0)])),
+        ]);
+        let mut store = Store::default();
+        let exports = instantiate(&mut store, &bytes).exports;
+        let mem = exports.get_memory("mem").unwrap();
+        assert_eq!(mem.size(&store), PAGE_SIZE);
+        assert!(mem.data(&store).iter().all(|byte| *byte == 0));
+
+        mem.write(&mut store, PAGE_SIZE - 4, &[1, 2, 3, 4]).unwrap();
+        let mut buf = [0; 4];
+        mem.read(&store, PAGE_SIZE - 4, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+        assert!(mem.write(&mut store, PAGE_SIZE - 3, &[1, 2, 3, 4]).is_err());
+        assert!(mem.read(&store, usize::MAX, &mut buf).is_err());
+        assert!(exports.get_memory("nope").is_err());
+
+        // A max below the min, a min too large and unknown limits flags
+        for memory in [
+            vec![0x01, 2, 1],
+            vec![0x00, 0x81, 0x80, 0x04],
+            vec![0x02, 0],
+        ] {
+            let bytes = module(vec![section(5, vec(vec![memory]))]);
+            assert!(Module::from_bytes(&store, &bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn load_and_store() {
+        // Stores p1 at p0 + 4 and loads it back
+        let code = vec![0x20, 0, 0x20, 1, 0x36, 2, 4, 0x20, 0, 0x28, 2, 4, 0x0b];
+        let bytes = funcs_module(
+            vec![("f", func_type(&[I32, I32], &[I32]), code)],
+            vec![export("mem", MEMORY, 0)],
+            vec![section(5, vec(vec![vec![0x00, 1]]))],
+        );
+        let mut store = Store::default();
+        let exports = instantiate(&mut store, &bytes).exports;
+        let f = exports.get_function("f").unwrap();
+        let mut store_at = |addr| f.call(&mut store, &[Value::I32(addr), Value::I32(0x01020304)]);
+        assert_eq!(store_at(8).unwrap(), [Value::I32(0x01020304)]);
+        assert!(store_at(PAGE_SIZE as i32 - 8).is_ok());
+        assert_eq!(
+            trap_kind(store_at(PAGE_SIZE as i32 - 7)),
+            TrapKind::OutOfBounds
+        );
+        assert_eq!(trap_kind(store_at(-1)), TrapKind::OutOfBounds);
+
+        // Little endian
+        let mem = exports.get_memory("mem").unwrap();
+        assert_eq!(mem.data(&store)[12..16], [4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn empty_function() {
+        let bytes = funcs_module(
+            vec![("noop", func_type(&[], &[]), vec![0x0b])],
+            vec![],
+            vec![],
+        );
+        let mut store = Store::default();
+        let noop = instantiate(&mut store, &bytes)
+            .exports
+            .get_function("noop")
+            .unwrap();
+        assert_eq!(noop.call(&mut store, &[]).unwrap(), []);
+    }
+
+    #[test]
+    fn data_section() {
+        let with_data_at = |offset| {
+            funcs_module(
+                vec![(
+                    "f",
+                    func_type(&[I32], &[I32]),
+                    vec![0x20, 0, 0x2d, 0, 0, 0x0b],
+                )],
+                vec![],
+                vec![
+                    section(5, vec(vec![vec![0x00, 1]])),
+                    section(11, vec(vec![active_data(offset, b"hi"), passive_data(&[])])),
+                ],
+            )
+        };
+        let mut store = Store::default();
+        let f = func(&mut store, &with_data_at(0));
+        let mut load = |addr| f.call(&mut store, &[Value::I32(addr)]);
+        assert_eq!(load(0).unwrap(), [Value::I32(b'h' as i32)]);
+        assert_eq!(load(1).unwrap(), [Value::I32(b'i' as i32)]);
+        assert_eq!(load(2).unwrap(), [Value::I32(0)]);
+
+        // The offset is unsigned
+        let module = Module::from_bytes(&store, &with_data_at(-1)).unwrap();
+        let e = Instance::new(&mut store, module, &Imports::new())
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("out of bounds"), "{e}");
+    }
+
+    #[test]
+    fn deadline() {
+        // loop br 0 end, forever
+        let bytes = single_func(&[], &[], &[], vec![0x03, 0x40, 0x0c, 0, 0x0b, 0x0b]);
+        let mut store = Store::default();
+        let f = func(&mut store, &bytes);
+        store.set_deadline(Instant::now() + Duration::from_millis(10));
+        assert_eq!(trap_kind(f.call(&mut store, &[])), TrapKind::Timeout);
+
+        // The passed deadline keeps trapping until it is cleared
+        let bytes = single_func(&[], &[I32], &[], vec![0x41, 1, 0x0b]);
+        let one = func(&mut store, &bytes);
+        assert_eq!(trap_kind(one.call(&mut store, &[])), TrapKind::Timeout);
+        store.clear_deadline();
+        assert_eq!(one.call(&mut store, &[]).unwrap(), [Value::I32(1)]);
+    }
+
+    #[test]
+    fn memory_grow() {
+        let bytes = funcs_module(
+            vec![
+                (
+                    "grow",
+                    func_type(&[I32], &[I32]),
+                    vec![0x20, 0, 0x40, 0, 0x0b],
+                ),
+                ("size", func_type(&[], &[I32]), vec![0x3f, 0, 0x0b]),
+            ],
+            vec![],
+            vec![section(5, vec(vec![vec![0x01, 1, 3]]))],
+        );
+        let mut store = Store::default();
+        let exports = instantiate(&mut store, &bytes).exports;
+        let grow = exports.get_function("grow").unwrap();
+        let size = exports.get_function("size").unwrap();
+        assert_eq!(size.call(&mut store, &[]).unwrap(), [Value::I32(1)]);
+        assert_eq!(
+            grow.call(&mut store, &[Value::I32(1)]).unwrap(),
+            [Value::I32(1)]
+        );
+        assert_eq!(size.call(&mut store, &[]).unwrap(), [Value::I32(2)]);
+        // Past the maximum of 3 pages
+        assert_eq!(
+            grow.call(&mut store, &[Value::I32(2)]).unwrap(),
+            [Value::I32(-1)]
+        );
+        assert_eq!(size.call(&mut store, &[]).unwrap(), [Value::I32(2)]);
+    }
+
+    #[test]
+    fn loop_sum() {
+        // Adds the counter in local 0 to local 1 while it's below 5
+        let bytes = single_func(
+            &[],
+            &[I32],
+            &[(2, I32)],
+            vec![
+                0x03, 0x40, //
+                0x20, 1, 0x20, 0, 0x6a, 0x21, 1, //
+                0x20, 0, 0x41, 1, 0x6a, 0x22, 0, //
+                0x41, 5, 0x48, 0x0d, 0, //
+                0x0b, 0x20, 1, 0x0b,
+            ],
+        );
+        let mut store = Store::default();
+        let f = func(&mut store, &bytes);
+        assert_eq!(f.call(&mut store, &[]).unwrap(), [Value::I32(10)]);
+    }
+
+    #[test]
+    fn branch_out_of_nested_blocks() {
+        // 9 + (block (result i32) (block 1 2 3 br 1) 8)
+        let bytes = single_func(
+            &[],
+            &[I32],
+            &[],
+            vec![
+                0x41, 9, 0x02, I32, 0x02, 0x40, 0x41, 1, 0x41, 2, 0x41, 3, 0x0c, 1, 0x0b, 0x41, 8,
+                0x0b, 0x6a, 0x0b,
+            ],
+        );
+        let mut store = Store::default();
+        let f = func(&mut store, &bytes);
+        assert_eq!(f.call(&mut store, &[]).unwrap(), [Value::I32(12)]);
+    }
+
+    #[test]
+    fn if_else() {
+        let bytes = funcs_module(
+            vec![
+                (
+                    "pick",
+                    func_type(&[I32], &[I32]),
+                    vec![0x20, 0, 0x04, I32, 0x41, 10, 0x05, 0x41, 20, 0x0b, 0x0b],
+                ),
+                // Without an else, a zero condition skips to the end
+                (
+                    "no_else",
+                    func_type(&[I32], &[I32]),
+                    vec![0x20, 0, 0x04, 0x40, 0x41, 2, 0x21, 0, 0x0b, 0x20, 0, 0x0b],
+                ),
+            ],
+            vec![],
+            vec![],
+        );
+        let mut store = Store::default();
+        let exports = instantiate(&mut store, &bytes).exports;
+        let pick = exports.get_function("pick").unwrap();
+        assert_eq!(
+            pick.call(&mut store, &[Value::I32(1)]).unwrap(),
+            [Value::I32(10)]
+        );
+        assert_eq!(
+            pick.call(&mut store, &[Value::I32(0)]).unwrap(),
+            [Value::I32(20)]
+        );
+        let no_else = exports.get_function("no_else").unwrap();
+        assert_eq!(
+            no_else.call(&mut store, &[Value::I32(7)]).unwrap(),
+            [Value::I32(2)]
+        );
+        assert_eq!(
+            no_else.call(&mut store, &[Value::I32(0)]).unwrap(),
+            [Value::I32(0)]
+        );
+    }
+
+    #[test]
+    fn const_arithmetic() {
+        // (func (result i32) i32.const 2 i32.const 3 i32.add)
+        let bytes = single_func(&[], &[I32], &[], vec![0x41, 2, 0x41, 3, 0x6a, 0x0b]);
+        let mut store = Store::default();
+        let f = func(&mut store, &bytes);
+        assert_eq!(f.call(&mut store, &[]).unwrap(), [Value::I32(5)]);
+
+        // The first operand is the one pushed first
+        let bytes = single_func(&[], &[I32], &[], vec![0x41, 2, 0x41, 3, 0x6b, 0x0b]);
+        let f = func(&mut store, &bytes);
+        assert_eq!(f.call(&mut store, &[]).unwrap(), [Value::I32(-1)]);
+    }
+
+    #[test]
+    fn preallocate_max_memory() {
+        let bytes = funcs_module(
+            vec![(
+                "grow",
+                func_type(&[I32], &[I32]),
+                vec![0x20, 0, 0x40, 0, 0x0b],
+            )],
+            vec![],
+            vec![section(5, vec(vec![vec![0x01, 1, 3]]))],
+        );
+        let mut store = Store::new(Config {
+            preallocate_max_memory: true,
+            ..Default::default()
+        });
+        let grow = instantiate(&mut store, &bytes)
+            .exports
+            .get_function("grow")
+            .unwrap();
+        assert_eq!(store.memories[0].len(), PAGE_SIZE);
+        assert_eq!(store.memories[0].capacity(), 3 * PAGE_SIZE);
+
+        let ptr = store.memories[0].as_ptr();
+        assert_eq!(
+            grow.call(&mut store, &[Value::I32(2)]).unwrap(),
+            [Value::I32(1)]
+        );
+        assert_eq!(store.memories[0].len(), 3 * PAGE_SIZE);
+        assert_eq!(store.memories[0].as_ptr(), ptr);
+    }
+
+    #[test]
+    fn unreachable_and_return() {
+        let bytes = funcs_module(
+            vec![
+                ("trap", func_type(&[], &[]), vec![0x01, 0x00, 0x0b]),
+                // Returns from inside two blocks, skipping the 2
+                (
+                    "early",
+                    func_type(&[], &[I32]),
+                    vec![
+                        0x02, 0x40, 0x02, 0x40, 0x41, 1, 0x0f, 0x0b, 0x0b, 0x41, 2, 0x0b,
+                    ],
+                ),
+            ],
+            vec![],
+            vec![],
+        );
+        let mut store = Store::default();
+        let exports = instantiate(&mut store, &bytes).exports;
+        let trap = exports.get_function("trap").unwrap();
+        assert_eq!(trap_kind(trap.call(&mut store, &[])), TrapKind::Unreachable);
+        let early = exports.get_function("early").unwrap();
+        assert_eq!(early.call(&mut store, &[]).unwrap(), [Value::I32(1)]);
+    }
+
+    #[test]
+    fn signed_and_unsigned_compare() {
+        let compare = |store: &mut Store, opcode: u8, a: i32, b: i32| {
+            let bytes = single_func(
+                &[I32, I32],
+                &[I32],
+                &[],
+                vec![0x20, 0, 0x20, 1, opcode, 0x0b],
+            );
+            let f = func(store, &bytes);
+            match f.call(store, &[Value::I32(a), Value::I32(b)]).unwrap()[..] {
+                [Value::I32(result)] => result,
+                ref other => panic!("unexpected results {other:?}"),
+            }
+        };
+        let mut store = Store::default();
+        // -1 is below 1 signed, but is u32::MAX unsigned
+        assert_eq!(compare(&mut store, 0x48, -1, 1), 1); // lt_s
+        assert_eq!(compare(&mut store, 0x49, -1, 1), 0); // lt_u
+        assert_eq!(compare(&mut store, 0x4a, -1, 1), 0); // gt_s
+        assert_eq!(compare(&mut store, 0x4b, -1, 1), 1); // gt_u
+        assert_eq!(compare(&mut store, 0x4c, -1, -1), 1); // le_s
+        assert_eq!(compare(&mut store, 0x4e, i32::MIN, 0), 0); // ge_s
+        assert_eq!(compare(&mut store, 0x46, -1, -1), 1); // eq
+        assert_eq!(compare(&mut store, 0x47, -1, -1), 0); // ne
+
+        let bytes = single_func(&[I32], &[I32], &[], vec![0x20, 0, 0x45, 0x0b]);
+        let eqz = func(&mut store, &bytes);
+        assert_eq!(
+            eqz.call(&mut store, &[Value::I32(0)]).unwrap(),
+            [Value::I32(1)]
+        );
+        assert_eq!(
+            eqz.call(&mut store, &[Value::I32(-1)]).unwrap(),
+            [Value::I32(0)]
+        );
+    }
+
+    #[test]
+    fn i32_div_overflow_and_shifts() {
+        let binary = |store: &mut Store, opcode: u8, a: i32, b: i32| {
+            let bytes = single_func(
+                &[I32, I32],
+                &[I32],
+                &[],
+                vec![0x20, 0, 0x20, 1, opcode, 0x0b],
+            );
+            let f = func(store, &bytes);
+            f.call(store, &[Value::I32(a), Value::I32(b)])
+        };
+        let mut store = Store::default();
+        assert_eq!(
+            trap_kind(binary(&mut store, 0x6d, i32::MIN, -1)),
+            TrapKind::IntegerOverflow
+        );
+        assert_eq!(
+            trap_kind(binary(&mut store, 0x6d, 1, 0)),
+            TrapKind::DivByZero
+        );
+        // The remainder of the same division doesn't overflow
+        assert_eq!(
+            binary(&mut store, 0x6f, i32::MIN, -1).unwrap(),
+            [Value::I32(0)]
+        );
+
+        // Shift counts are taken modulo 32
+        assert_eq!(binary(&mut store, 0x74, 1, 33).unwrap(), [Value::I32(2)]);
+        assert_eq!(binary(&mut store, 0x75, -8, 34).unwrap(), [Value::I32(-2)]);
+        assert_eq!(binary(&mut store, 0x76, -8, 32).unwrap(), [Value::I32(-8)]);
+        assert_eq!(binary(&mut store, 0x76, -1, -1).unwrap(), [Value::I32(1)]);
+    }
+
+    #[test]
+    fn call_memory_is_checked_before_executing() {
+        let bytes = funcs_module(
+            vec![
+                // Sets the global to 1, then needs 1 local and 3 stack slots
+                (
+                    "big",
+                    func_type(&[I32], &[I32]),
+                    vec![
+                        0x41, 1, 0x24, 0, 0x41, 1, 0x41, 2, 0x41, 3, 0x6a, 0x6a, 0x0b,
+                    ],
+                ),
+                ("get", func_type(&[], &[I32]), vec![0x23, 0, 0x0b]),
+            ],
+            vec![],
+            vec![section(6, vec(vec![vec![I32, 1, 0x41, 0, 0x0b]]))],
+        );
+        let mut store = Store::new(Config {
+            max_call_memory_slots: Some(3),
+            ..Default::default()
+        });
+        let exports = instantiate(&mut store, &bytes).exports;
+        let big = exports.get_function("big").unwrap();
+        let e = big.call(&mut store, &[Value::I32(0)]).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("call needs 3 stack slots and 1 locals"),
+            "{e}"
+        );
+        let get = exports.get_function("get").unwrap();
+        assert_eq!(get.call(&mut store, &[]).unwrap(), [Value::I32(0)]);
+    }
+
+    #[test]
+    fn i32_add_and_mul_wrap() {
+        let mut store = Store::default();
+        let mut binary = |opcode: u8, a: i32, b: i32| {
+            let code = [
+                vec![0x41],
+                sleb(a as i64),
+                vec![0x41],
+                sleb(b as i64),
+                vec![opcode, 0x0b],
+            ]
+            .concat();
+            let f = func(&mut store, &single_func(&[], &[I32], &[], code));
+            f.call(&mut store, &[]).unwrap()
+        };
+        assert_eq!(binary(0x6a, i32::MAX, 1), [Value::I32(i32::MIN)]);
+        assert_eq!(binary(0x6a, i32::MIN, -1), [Value::I32(i32::MAX)]);
+        assert_eq!(binary(0x6c, i32::MAX, 2), [Value::I32(-2)]);
+        assert_eq!(binary(0x6c, i32::MIN, -1), [Value::I32(i32::MIN)]);
+        assert_eq!(binary(0x6c, 0x10000, 0x10000), [Value::I32(0)]);
+    }
+
+    #[test]
+    fn function_names_in_errors() {
+        let subsection = vec(vec![[leb(0), name("boom")].concat()]);
+        let names = [
+            name("name"),
+            vec![1],
+            leb(subsection.len() as u64),
+            subsection,
+        ]
+        .concat();
+        let bytes = funcs_module(
+            vec![("f", func_type(&[], &[]), vec![0x00, 0x0b])],
+            vec![],
+            vec![section(0, names)],
+        );
+        let mut store = Store::default();
+        let f = func(&mut store, &bytes);
+        let e = f.call(&mut store, &[]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "trap in function 0 (boom): unreachable executed"
+        );
+        let e = f.call(&mut store, &[Value::I32(1)]).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("function 0 (boom) expects 0 arguments"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn trap_kinds() {
+        let mut store = Store::default();
+        let mut run = |code: Vec<u8>| {
+            let bytes = funcs_module(
+                vec![("f", func_type(&[], &[I32]), code)],
+                vec![],
+                vec![section(5, vec(vec![vec![0x00, 1]]))],
+            );
+            let f = func(&mut store, &bytes);
+            f.call(&mut store, &[])
+        };
+        let cases = [
+            (vec![0x41, 1, 0x41, 0, 0x6d, 0x0b], TrapKind::DivByZero),
+            (
+                vec![0x41, 0x80, 0x80, 0x80, 0x80, 0x78, 0x41, 0x7f, 0x6d, 0x0b],
+                TrapKind::IntegerOverflow,
+            ),
+            (vec![0x41, 0x7f, 0x28, 2, 0, 0x0b], TrapKind::OutOfBounds),
+            (vec![0x00, 0x0b], TrapKind::Unreachable),
+            // Calls itself forever
+            (vec![0x10, 0, 0x0b], TrapKind::StackExhausted),
+        ];
+        for (code, kind) in cases {
+            assert_eq!(trap_kind(run(code)), kind);
+        }
+
+        // Traps still compose with other errors
+        let e: Box<dyn std::error::Error> = Box::new(run(vec![0x00, 0x0b]).unwrap_err());
+        assert!(e.to_string().contains("unreachable executed"), "{e}");
+    }
+
+    #[test]
+    fn call_arguments_are_checked() {
+        let bytes = single_func(&[I32, I32], &[I32], &[], vec![0x20, 0, 0x20, 1, 0x6a, 0x0b]);
+        let mut store = Store::default();
+        let add = func(&mut store, &bytes);
+        let message = |args: &[Value], store: &mut Store| match add.call(store, args) {
+            Err(WasmError::Validation(message)) => message,
+            other => panic!("expected a validation error, got {other:?}"),
+        };
+        assert_eq!(
+            message(&[Value::I32(1)], &mut store),
+            "function 0 expects 2 arguments, got 1"
+        );
+        assert_eq!(
+            message(&[Value::I32(1), Value::I32(2), Value::I32(3)], &mut store),
+            "function 0 expects 2 arguments, got 3"
+        );
+        assert_eq!(
+            message(&[Value::I32(1), Value::F32(2.0)], &mut store),
+            "function 0 expects argument 1 of type I32, got F32(2.0)"
+        );
+    }
+
+    #[test]
+    fn start_function_errors() {
+        let with_start = |ty: Vec<u8>, code: Vec<u8>, start: u32| {
+            module(vec![
+                section(1, vec(vec![ty])),
+                section(3, vec(vec![leb(0)])),
+                section(8, leb(start as u64)),
+                section(10, vec(vec![body(&[], code)])),
+            ])
+        };
+        let mut store = Store::default();
+        let mut instantiate = |bytes: Vec<u8>| {
+            let module = Module::from_bytes(&store, &bytes).unwrap();
+            let e = Instance::new(&mut store, module, &Imports::new())
+                .err()
+                .unwrap();
+            format!("{e:#}")
+        };
+
+        // Traps are propagated
+        let e = instantiate(with_start(func_type(&[], &[]), vec![0x00, 0x0b], 0));
+        assert!(
+            e.starts_with("trap in start function 0: trap in function 0: unreachable executed"),
+            "{e}"
+        );
+        let e = instantiate(with_start(func_type(&[I32], &[]), vec![0x0b], 0));
+        assert_eq!(
+            e,
+            "start function 0 has type FuncType { params: [I32], results: [] }, expected no params and no results"
+        );
+        let e = instantiate(with_start(func_type(&[], &[]), vec![0x0b], 1));
+        assert_eq!(e, "start function 1 doesn't exist");
+    }
+
+    #[test]
+    fn huge_memory_grow() {
+        // A memory of 1 page without a maximum
+        let bytes = funcs_module(
+            vec![
+                (
+                    "grow",
+                    func_type(&[I32], &[I32]),
+                    vec![0x20, 0, 0x40, 0, 0x0b],
+                ),
+                ("size", func_type(&[], &[I32]), vec![0x3f, 0, 0x0b]),
+            ],
+            vec![],
+            vec![section(5, vec(vec![vec![0x00, 1]]))],
+        );
+        let mut store = Store::default();
+        let exports = instantiate(&mut store, &bytes).exports;
+        let grow = exports.get_function("grow").unwrap();
+        let size = exports.get_function("size").unwrap();
+        // Past 4GiB, and u32::MAX pages
+        for delta in [MAX_PAGES as i32, -1] {
+            assert_eq!(
+                grow.call(&mut store, &[Value::I32(delta)]).unwrap(),
+                [Value::I32(-1)]
+            );
+        }
+        assert_eq!(size.call(&mut store, &[]).unwrap(), [Value::I32(1)]);
+
+        // The maximum comes from the config instead
+        let mut store = Store::new(Config {
+            max_memory_pages: Some(16),
+            ..Default::default()
+        });
+        let exports = instantiate(&mut store, &bytes).exports;
+        let grow = exports.get_function("grow").unwrap();
+        assert_eq!(
+            grow.call(&mut store, &[Value::I32(16)]).unwrap(),
+            [Value::I32(-1)]
+        );
+        assert_eq!(
+            grow.call(&mut store, &[Value::I32(15)]).unwrap(),
+            [Value::I32(1)]
+        );
+        assert_eq!(store.memories[0].len(), 16 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn call_memory_counts_declared_locals() {
+        // "big" declares 40000 locals, "tail" tail calls it
+        let bytes = module(vec![
+            section(1, vec(vec![func_type(&[], &[])])),
+            section(3, vec(vec![leb(0), leb(0)])),
+            section(
+                7,
+                vec(vec![export("big", FUNC, 0), export("tail", FUNC, 1)]),
+            ),
+            section(
+                10,
+                vec(vec![
+                    body(&[(40_000, I32)], vec![0x0b]),
+                    body(&[], vec![0x12, 0, 0x0b]),
+                ]),
+            ),
+        ]);
+        let mut store = Store::new(Config {
+            max_call_memory_slots: Some(100),
+            ..Default::default()
+        });
+        let exports = instantiate(&mut store, &bytes).exports;
+        for name in ["big", "tail"] {
+            let f = exports.get_function(name).unwrap();
+            let e = f.call(&mut store, &[]).unwrap_err();
+            assert!(
+                e.to_string()
+                    .contains("call needs 0 stack slots and 40000 locals"),
+                "{name}: {e}"
+            );
+        }
+    }
+
+    #[test]
+    fn const_exprs_read_immutable_globals() {
+        // Global 1 is initialized from global 0, which is only allowed while
+        // global 0 is immutable
+        let with_global_0 = |mutable: u8| {
+            funcs_module(
+                vec![("f", func_type(&[], &[I32]), vec![0x23, 1, 0x0b])],
+                vec![],
+                vec![section(
+                    6,
+                    vec(vec![
+                        vec![I32, mutable, 0x41, 7, 0x0b],
+                        vec![I32, 0, 0x23, 0, 0x0b],
+                    ]),
+                )],
+            )
+        };
+        let mut store = Store::default();
+        let f = func(&mut store, &with_global_0(0));
+        assert_eq!(f.call(&mut store, &[]).unwrap(), [Value::I32(7)]);
+
+        let module = Module::from_bytes(&store, &with_global_0(1)).unwrap();
+        let e = Instance::new(&mut store, module, &Imports::new())
+            .err()
+            .unwrap();
+        assert_eq!(
+            format!("{e:#}"),
+            "initialize global 1: global 0 is mutable, it can't be used in a constant expression"
+        );
     }
 }
@@ -0,0 +1,99 @@
+//! Bounds-checked LEB128 decoding, and encoding.
+
+use anyhow::{anyhow, bail, Result};
+
+fn map_err(err: leb128::read::Error) -> anyhow::Error {
+    match err {
+        leb128::read::Error::IoError(_) => anyhow!("unexpected end of input in LEB128 value"),
+        leb128::read::Error::Overflow => anyhow!("LEB128 value is too long"),
+    }
+}
+
+// A 32-bit value is encoded in at most 5 bytes. Wider encodings are
+// malformed even if the padding bytes don't change the value.
+const MAX_LEN_32: usize = 5;
+
+// Reads a value with the 64-bit `read`, rejecting encodings that are too long
+// for a 32-bit one.
+fn read_32<T>(contents: &mut &[u8], read: fn(&mut &[u8]) -> Result<T>) -> Result<T> {
+    let len = contents.len();
+    let n = read(contents)?;
+    if len - contents.len() > MAX_LEN_32 {
+        bail!("LEB128 value is too long");
+    }
+    Ok(n)
+}
+
+pub fn read_u64(contents: &mut &[u8]) -> Result<u64> {
+    leb128::read::unsigned(contents).map_err(map_err)
+}
+
+pub fn read_u32(contents: &mut &[u8]) -> Result<u32> {
+    let n = read_32(contents, read_u64)?;
+    match u32::try_from(n) {
+        Ok(n) => Ok(n),
+        Err(_) => bail!("LEB128 value {n} doesn't fit in an u32"),
+    }
+}
+
+pub fn read_i64(contents: &mut &[u8]) -> Result<i64> {
+    leb128::read::signed(contents).map_err(map_err)
+}
+
+// The sign extension of the 64-bit decoding makes the range check also reject
+// final bytes whose unused bits don't match the sign.
+pub fn read_i32(contents: &mut &[u8]) -> Result<i32> {
+    let n = read_32(contents, read_i64)?;
+    match i32::try_from(n) {
+        Ok(n) => Ok(n),
+        Err(_) => bail!("LEB128 value {n} doesn't fit in an i32"),
+    }
+}
+
+pub fn write_u32(out: &mut Vec<u8>, n: u32) {
+    leb128::write::unsigned(out, n as u64).expect("writing to a Vec can't fail");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated() {
+        let e = read_u32(&mut &[0x80, 0x80][..]).unwrap_err();
+        assert_eq!(e.to_string(), "unexpected end of input in LEB128 value");
+        let e = read_i64(&mut &[0xff][..]).unwrap_err();
+        assert_eq!(e.to_string(), "unexpected end of input in LEB128 value");
+    }
+
+    #[test]
+    fn overflowing() {
+        let bytes = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f];
+        let e = read_u64(&mut &bytes[..]).unwrap_err();
+        assert_eq!(e.to_string(), "LEB128 value is too long");
+        let e = read_u32(&mut &[0xff, 0xff, 0xff, 0xff, 0x7f][..]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "LEB128 value 34359738367 doesn't fit in an u32"
+        );
+        let e = read_u32(&mut &[0x81, 0x80, 0x80, 0x80, 0x80, 0][..]).unwrap_err();
+        assert_eq!(e.to_string(), "LEB128 value is too long");
+        assert_eq!(
+            read_i32(&mut &[0xff, 0xff, 0xff, 0xff, 0x7f][..]).unwrap(),
+            -1
+        );
+    }
+
+    #[test]
+    fn signed_boundaries() {
+        let read = |bytes: &[u8]| read_i32(&mut &bytes[..]).map_err(|e| e.to_string());
+        assert_eq!(read(&[0x80, 0x80, 0x80, 0x80, 0x78]), Ok(i32::MIN));
+        assert_eq!(read(&[0xff, 0xff, 0xff, 0xff, 0x07]), Ok(i32::MAX));
+        // Bits past the 32nd must be the sign extension
+        assert!(read(&[0xff, 0xff, 0xff, 0xff, 0x0f]).is_err());
+        assert_eq!(
+            read(&[0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]),
+            Err("LEB128 value is too long".to_string())
+        );
+    }
+}
@@ -0,0 +1,15 @@
+pub mod compile;
+pub mod config;
+pub mod coredump;
+pub mod error;
+pub mod imports;
+pub mod instance;
+pub mod leb;
+pub mod lint;
+pub mod module;
+pub mod profile;
+pub mod store;
+#[cfg(test)]
+mod test_util;
+pub mod typed;
+pub mod validate;
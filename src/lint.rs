@@ -0,0 +1,161 @@
+//! Validation reporting every problem of a module instead of stopping at the
+//! first one, for tools that want to show them all.
+
+use std::fmt::Display;
+
+use crate::{module::Module, validate::validate_body_all};
+
+/// A problem found by [`Module::lint`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidationIssue {
+    /// Index of the function the problem is in, `None` for problems outside
+    /// of function bodies.
+    pub func: Option<u32>,
+    /// Index in the function body of the offending instruction, when known.
+    pub offset: Option<usize>,
+    pub message: String,
+}
+
+impl Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.func, self.offset) {
+            (Some(func), Some(offset)) => write!(f, "function {func} at {offset}: ")?,
+            (Some(func), None) => write!(f, "function {func}: ")?,
+            _ => {}
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Module {
+    /// Validates the module without instantiating it, returning every problem
+    /// found. An empty result means the module passed.
+    pub fn lint(&self) -> Vec<ValidationIssue> {
+        let mut issues = Vec::new();
+        let mut module_issue = |message: String| {
+            issues.push(ValidationIssue {
+                func: None,
+                offset: None,
+                message,
+            })
+        };
+
+        for export in self.unknown_exports() {
+            module_issue(format!(
+                "export {} refers to unknown {:?} {}",
+                export.name, export.kind, export.idx
+            ));
+        }
+        if let Some(start) = self.start {
+            if self.func_type(start).is_none() {
+                module_issue(format!("start function {start} doesn't exist"));
+            }
+        }
+
+        for idx in 0..self.funcs.len() as u32 {
+            self.lint_func(idx + self.num_imported_funcs(), &mut issues);
+        }
+
+        issues
+    }
+
+    fn lint_func(&self, idx: u32, issues: &mut Vec<ValidationIssue>) {
+        let func = self
+            .defined_func(idx)
+            .expect("only called for defined functions");
+        let mut issue = |offset: Option<usize>, message: String| {
+            issues.push(ValidationIssue {
+                func: Some(idx),
+                offset,
+                message,
+            })
+        };
+
+        let body = match func.body() {
+            Ok(body) => body,
+            Err(e) => return issue(None, format!("{e:#}")),
+        };
+
+        for e in validate_body_all(self, func, body) {
+            issue(e.offset, format!("{:#}", e.error));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{store::Store, test_util::*};
+
+    use super::*;
+
+    #[test]
+    fn reports_every_problem() {
+        let bytes = funcs_module(
+            vec![
+                ("call", func_type(&[], &[]), vec![0x10, 5, 0x0b]),
+                ("local", func_type(&[], &[I32]), vec![0x20, 3, 0x0b]),
+                // Returns an i64 instead of an i32
+                ("mismatch", func_type(&[], &[I32]), vec![0x42, 1, 0x0b]),
+            ],
+            vec![],
+            vec![],
+        );
+        let parsed = Module::from_bytes(&Store::default(), &bytes).unwrap();
+        let issues = parsed.lint();
+        assert_eq!(issues.len(), 3, "{issues:?}");
+        assert_eq!(
+            issues[0],
+            ValidationIssue {
+                func: Some(0),
+                offset: Some(0),
+                message: "call to unknown function 5".to_string(),
+            }
+        );
+        assert_eq!(issues[1].to_string(), "function 1 at 0: unknown local 3");
+        assert_eq!(issues[2].func, Some(2));
+
+        let bytes = single_func(&[], &[], &[], vec![0x0b]);
+        let parsed = Module::from_bytes(&Store::default(), &bytes).unwrap();
+        assert_eq!(parsed.lint(), []);
+    }
+
+    #[test]
+    fn reports_every_problem_of_a_function() {
+        let bytes = funcs_module(
+            vec![
+                // No memory to fill
+                (
+                    "fill",
+                    func_type(&[], &[]),
+                    vec![0x41, 0, 0x41, 0, 0x41, 0, 0xfc, 11, 0, 0x0b],
+                ),
+                // An unknown local, then an i64 added to an i32
+                (
+                    "both",
+                    func_type(&[], &[I32]),
+                    vec![0x20, 3, 0x41, 1, 0x42, 1, 0x6a, 0x0b],
+                ),
+                // A block of unknown type 63, which its end still closes
+                (
+                    "block",
+                    func_type(&[], &[]),
+                    vec![0x02, 0x3f, 0x0b, 0x10, 9, 0x0b],
+                ),
+            ],
+            vec![],
+            vec![],
+        );
+        let parsed = Module::from_bytes(&Store::default(), &bytes).unwrap();
+        let issues: Vec<_> = parsed.lint().iter().map(|i| i.to_string()).collect();
+        assert_eq!(
+            issues,
+            [
+                "function 0 at 3: unknown memory 0",
+                "function 1 at 0: unknown local 3",
+                "function 1 at 3: expected an operand of type I32, got I64",
+                "function 2 at 0: unknown block type Func(63)",
+                "function 2 at 2: call to unknown function 9",
+            ]
+        );
+    }
+}
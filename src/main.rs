@@ -1,20 +1,71 @@
+use std::process::ExitCode;
+
 use anyhow::Result;
-use instance::{Instance, Value};
-use module::Module;
-use store::Store;
+use rasm::{
+    imports::Imports,
+    instance::{Instance, Value},
+    module::Module,
+    store::Store,
+};
 
-mod instance;
-mod module;
-mod store;
+fn main() -> ExitCode {
+    let (out, err, code) = report(run());
+    print!("{out}");
+    eprint!("{err}");
+    code
+}
 
-fn main() -> Result<()> {
+fn run() -> Result<Vec<Value>> {
     let mut store = Store::default();
     let module = Module::from_file(&store, "example2.wasm")?;
-    let instance = Instance::new(&mut store, module)?;
+    let instance = Instance::new(&mut store, module, &Imports::new())?;
     let add = instance.exports.get_function("add")?;
-    let result = add.call(&mut store, &[Value::I32(12), Value::I32(42), Value::I32(2)])?;
+    Ok(add.call(&mut store, &[Value::I32(12), Value::I32(42), Value::I32(2)])?)
+}
+
+// What to print on stdout and stderr for the outcome of the call, along with
+// the exit code.
+fn report(result: Result<Vec<Value>>) -> (String, String, ExitCode) {
+    match result {
+        Ok(results) => (format_results(&results), String::new(), ExitCode::SUCCESS),
+        Err(e) => (String::new(), format!("error: {e:#}\n"), ExitCode::FAILURE),
+    }
+}
+
+// One result per line so the output is easy to consume from a shell.
+fn format_results(results: &[Value]) -> String {
+    results.iter().map(|value| format!("{value}\n")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multiple_results() {
+        let (out, err, code) = report(Ok(vec![Value::I32(56), Value::I64(-1)]));
+        assert_eq!(out, "56\n-1\n");
+        assert_eq!(err, "");
+        assert_eq!(code, ExitCode::SUCCESS);
+    }
 
-    println!("{result}");
+    #[test]
+    fn trap() {
+        // A module exporting a function "f" running unreachable
+        let bytes = b"\0asm\x01\0\0\0\
+            \x01\x04\x01\x60\0\0\
+            \x03\x02\x01\0\
+            \x07\x05\x01\x01f\0\0\
+            \x0a\x05\x01\x03\0\0\x0b";
+        let mut store = Store::default();
+        let module = Module::from_bytes(&store, bytes).unwrap();
+        let instance = Instance::new(&mut store, module, &Imports::new()).unwrap();
+        let f = instance.exports.get_function("f").unwrap();
+        let result = f.call(&mut store, &[]).map_err(Into::into);
 
-    Ok(())
+        let (out, err, code) = report(result);
+        assert_eq!(out, "");
+        assert_eq!(err, "error: trap in function 0: unreachable executed\n");
+        assert_eq!(code, ExitCode::FAILURE);
+    }
 }
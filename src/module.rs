@@ -1,12 +1,26 @@
-use crate::store::Store;
-use anyhow::{bail, Context, Result};
+use crate::{instance::Value, leb, store::Store};
+use anyhow::{anyhow, bail, Context, Result};
 use bytes::{Buf, BufMut};
-use std::path::Path;
+use std::{
+    cell::OnceCell,
+    collections::{HashMap, HashSet},
+    ops::Range,
+    path::Path,
+    rc::Rc,
+};
 
 static MAGIC: [u8; 4] = [0x00, 0x61, 0x73, 0x6D];
-static VERSION: [u8; 4] = [0x01, 0x00, 0x00, 0x00];
+
+// Same limits as the ones used by the major engines, they keep a malicious
+// type section from making us allocate huge vectors.
+const MAX_PARAMS: u32 = 1000;
+const MAX_RESULTS: u32 = 1000;
+// Same for the locals of a function body, which every call allocates.
+const MAX_LOCALS: u32 = 50_000;
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum Val {
     // Num types
     I32,
@@ -22,56 +36,652 @@ pub enum Val {
     ExternRef,
 }
 
+impl Val {
+    fn encode(&self) -> u8 {
+        match self {
+            Val::I32 => 0x7F,
+            Val::I64 => 0x7E,
+            Val::F32 => 0x7D,
+            Val::F64 => 0x7C,
+            Val::V128 => 0x7B,
+            Val::FuncRef => 0x70,
+            Val::ExternRef => 0x6F,
+        }
+    }
+
+    /// Returns whether `value` fits in a slot (param, local, global) of this
+    /// type.
+    pub fn accepts(&self, value: &Value) -> bool {
+        *self == value.ty()
+    }
+}
+
 #[derive(Clone, Default, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct FuncType {
     pub params: Vec<Val>,
     pub results: Vec<Val>,
 }
 
+impl FuncType {
+    pub fn new(params: Vec<Val>, results: Vec<Val>) -> Self {
+        Self { params, results }
+    }
+
+    /// Appends a parameter of type `val`.
+    pub fn param(mut self, val: Val) -> Self {
+        self.params.push(val);
+        self
+    }
+
+    /// Appends a result of type `val`.
+    pub fn result(mut self, val: Val) -> Self {
+        self.results.push(val);
+        self
+    }
+
+    /// Whether a function of type `self` can be used where one of type
+    /// `other` is expected, by an indirect call or an import. Without
+    /// subtyping this is structural equality.
+    pub fn is_compatible_with(&self, other: &FuncType) -> bool {
+        self.params == other.params && self.results == other.results
+    }
+}
+
+/// The immediates of a load or store instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemArg {
+    /// Alignment hint, as a power of two.
+    pub align: u32,
+    pub offset: u32,
+}
+
+/// The signature of a `block`, `loop` or `if`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlockType {
+    /// Takes nothing and leaves nothing on the stack.
+    Empty,
+    /// Takes nothing and leaves a single value.
+    Value(Val),
+    /// The params and results of a type of the type section.
+    Func(u32),
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Instr {
+    Unreachable,
+    Nop,
+    Block(BlockType),
+    Loop(BlockType),
+    If(BlockType),
+    Else,
+    Br(u32),
+    BrIf(u32),
+    Return,
+
     LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+
+    GlobalGet(u32),
+    GlobalSet(u32),
+
+    I32Load(MemArg),
+    I32Load8U(MemArg),
+    I32Store(MemArg),
+    MemorySize,
+    MemoryGrow,
 
-    LoadI32(i32),
+    ConstI32(i32),
+    ConstI64(i64),
+
+    I32Eqz,
+    I32Eq,
+    I32Ne,
+    I32LtS,
+    I32LtU,
+    I32GtS,
+    I32GtU,
+    I32LeS,
+    I32LeU,
+    I32GeS,
+    I32GeU,
 
     I32Add,
+    I32Sub,
     I32Mul,
+    I32DivS,
+    I32RemS,
+    I32RemU,
+    I32And,
+    I32Or,
+    I32Xor,
+    I32Shl,
+    I32ShrS,
+    I32ShrU,
+
+    I64Add,
+    I64Sub,
+    I64Mul,
+
+    F64Add,
+    F64Sub,
+    F64Mul,
+    F64Div,
 
     Call(u32),
+    ReturnCall(u32),
+    ReturnCallIndirect(u32, u32),
     DivI32U,
     End,
     ConstF64(f64),
+
+    F32DemoteF64,
+    F64PromoteF32,
+    I32ReinterpretF32,
+    I64ReinterpretF64,
+    F32ReinterpretI32,
+    F64ReinterpretI64,
+
+    // Bulk memory (0xFC prefix)
+    MemoryInit(u32),
+    DataDrop(u32),
+    MemoryCopy,
+    MemoryFill,
+
+    // Threads (0xFE prefix)
+    AtomicFence,
 }
 
-#[derive(Debug, PartialEq)]
+impl Instr {
+    /// The opcode byte this instruction is encoded with. For instructions
+    /// using a multi-byte opcode this is the prefix byte.
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Instr::Unreachable => 0x00,
+            Instr::Nop => 0x01,
+            Instr::Block(_) => 0x02,
+            Instr::Loop(_) => 0x03,
+            Instr::If(_) => 0x04,
+            Instr::Else => 0x05,
+            Instr::Br(_) => 0x0C,
+            Instr::BrIf(_) => 0x0D,
+            Instr::Return => 0x0F,
+            Instr::LocalGet(_) => 0x20,
+            Instr::LocalSet(_) => 0x21,
+            Instr::LocalTee(_) => 0x22,
+            Instr::GlobalGet(_) => 0x23,
+            Instr::GlobalSet(_) => 0x24,
+            Instr::I32Load(_) => 0x28,
+            Instr::I32Load8U(_) => 0x2D,
+            Instr::I32Store(_) => 0x36,
+            Instr::MemorySize => 0x3F,
+            Instr::MemoryGrow => 0x40,
+            Instr::ConstI32(_) => 0x41,
+            Instr::ConstI64(_) => 0x42,
+            Instr::I32Eqz => 0x45,
+            Instr::I32Eq => 0x46,
+            Instr::I32Ne => 0x47,
+            Instr::I32LtS => 0x48,
+            Instr::I32LtU => 0x49,
+            Instr::I32GtS => 0x4A,
+            Instr::I32GtU => 0x4B,
+            Instr::I32LeS => 0x4C,
+            Instr::I32LeU => 0x4D,
+            Instr::I32GeS => 0x4E,
+            Instr::I32GeU => 0x4F,
+            Instr::I32Add => 0x6A,
+            Instr::I32Mul => 0x6C,
+            Instr::I32Sub => 0x6B,
+            Instr::I32DivS => 0x6D,
+            Instr::I32RemS => 0x6F,
+            Instr::I32RemU => 0x70,
+            Instr::I32And => 0x71,
+            Instr::I32Or => 0x72,
+            Instr::I32Xor => 0x73,
+            Instr::I32Shl => 0x74,
+            Instr::I32ShrS => 0x75,
+            Instr::I32ShrU => 0x76,
+            Instr::I64Add => 0x7C,
+            Instr::I64Sub => 0x7D,
+            Instr::I64Mul => 0x7E,
+            Instr::F64Add => 0xA0,
+            Instr::F64Sub => 0xA1,
+            Instr::F64Mul => 0xA2,
+            Instr::F64Div => 0xA3,
+            Instr::Call(_) => 0x10,
+            Instr::ReturnCall(_) => 0x12,
+            Instr::ReturnCallIndirect(_, _) => 0x13,
+            Instr::DivI32U => 0x80,
+            Instr::End => 0x0B,
+            Instr::ConstF64(_) => 0x44,
+            Instr::F32DemoteF64 => 0xB6,
+            Instr::F64PromoteF32 => 0xBB,
+            Instr::I32ReinterpretF32 => 0xBC,
+            Instr::I64ReinterpretF64 => 0xBD,
+            Instr::F32ReinterpretI32 => 0xBE,
+            Instr::F64ReinterpretI64 => 0xBF,
+            Instr::MemoryInit(_) | Instr::DataDrop(_) | Instr::MemoryCopy | Instr::MemoryFill => {
+                0xFC
+            }
+            Instr::AtomicFence => 0xFE,
+        }
+    }
+
+    /// The types of the operands this instruction pops, bottom of the stack
+    /// first. Empty for instructions whose operands depend on the context,
+    /// like `call` or `global.set`.
+    pub(crate) fn operand_types(&self) -> &'static [Val] {
+        match self {
+            Instr::I32Add
+            | Instr::I32Mul
+            | Instr::DivI32U
+            | Instr::I32Sub
+            | Instr::I32DivS
+            | Instr::I32RemS
+            | Instr::I32RemU
+            | Instr::I32And
+            | Instr::I32Or
+            | Instr::I32Xor
+            | Instr::I32Shl
+            | Instr::I32ShrS
+            | Instr::I32ShrU
+            | Instr::I32Store(_)
+            | Instr::I32Eq
+            | Instr::I32Ne
+            | Instr::I32LtS
+            | Instr::I32LtU
+            | Instr::I32GtS
+            | Instr::I32GtU
+            | Instr::I32LeS
+            | Instr::I32LeU
+            | Instr::I32GeS
+            | Instr::I32GeU => &[Val::I32, Val::I32],
+            Instr::I64Add | Instr::I64Sub | Instr::I64Mul => &[Val::I64, Val::I64],
+            Instr::F64Add | Instr::F64Sub | Instr::F64Mul | Instr::F64Div => &[Val::F64, Val::F64],
+            Instr::I32Load(_)
+            | Instr::I32Load8U(_)
+            | Instr::MemoryGrow
+            | Instr::If(_)
+            | Instr::BrIf(_)
+            | Instr::I32Eqz
+            | Instr::F32ReinterpretI32 => &[Val::I32],
+            Instr::F32DemoteF64 | Instr::I64ReinterpretF64 => &[Val::F64],
+            Instr::F64PromoteF32 | Instr::I32ReinterpretF32 => &[Val::F32],
+            Instr::F64ReinterpretI64 => &[Val::I64],
+            Instr::MemoryInit(_) | Instr::MemoryCopy | Instr::MemoryFill => {
+                &[Val::I32, Val::I32, Val::I32]
+            }
+            Instr::Unreachable
+            | Instr::Nop
+            | Instr::Block(_)
+            | Instr::Loop(_)
+            | Instr::Else
+            | Instr::Br(_)
+            | Instr::Return
+            | Instr::LocalGet(_)
+            | Instr::LocalSet(_)
+            | Instr::LocalTee(_)
+            | Instr::GlobalGet(_)
+            | Instr::GlobalSet(_)
+            | Instr::MemorySize
+            | Instr::ConstI32(_)
+            | Instr::ConstI64(_)
+            | Instr::Call(_)
+            | Instr::ReturnCall(_)
+            | Instr::ReturnCallIndirect(_, _)
+            | Instr::End
+            | Instr::ConstF64(_)
+            | Instr::DataDrop(_)
+            | Instr::AtomicFence => &[],
+        }
+    }
+
+    /// The types of the values this instruction pushes, bottom of the stack
+    /// first. Empty for instructions whose results depend on the context,
+    /// like `call` or `local.get`.
+    pub(crate) fn result_types(&self) -> &'static [Val] {
+        match self {
+            Instr::I32Add
+            | Instr::I32Mul
+            | Instr::DivI32U
+            | Instr::I32Sub
+            | Instr::I32DivS
+            | Instr::I32RemS
+            | Instr::I32RemU
+            | Instr::I32And
+            | Instr::I32Or
+            | Instr::I32Xor
+            | Instr::I32Shl
+            | Instr::I32ShrS
+            | Instr::I32ShrU
+            | Instr::I32Eqz
+            | Instr::I32Eq
+            | Instr::I32Ne
+            | Instr::I32LtS
+            | Instr::I32LtU
+            | Instr::I32GtS
+            | Instr::I32GtU
+            | Instr::I32LeS
+            | Instr::I32LeU
+            | Instr::I32GeS
+            | Instr::I32GeU
+            | Instr::I32Load(_)
+            | Instr::I32Load8U(_)
+            | Instr::MemorySize
+            | Instr::MemoryGrow
+            | Instr::ConstI32(_)
+            | Instr::I32ReinterpretF32 => &[Val::I32],
+            Instr::I64Add
+            | Instr::I64Sub
+            | Instr::I64Mul
+            | Instr::ConstI64(_)
+            | Instr::I64ReinterpretF64 => &[Val::I64],
+            Instr::F64Add
+            | Instr::F64Sub
+            | Instr::F64Mul
+            | Instr::F64Div
+            | Instr::ConstF64(_)
+            | Instr::F64PromoteF32
+            | Instr::F64ReinterpretI64 => &[Val::F64],
+            Instr::F32DemoteF64 | Instr::F32ReinterpretI32 => &[Val::F32],
+            Instr::Unreachable
+            | Instr::Nop
+            | Instr::Block(_)
+            | Instr::Loop(_)
+            | Instr::If(_)
+            | Instr::Else
+            | Instr::Br(_)
+            | Instr::BrIf(_)
+            | Instr::Return
+            | Instr::LocalGet(_)
+            | Instr::LocalSet(_)
+            | Instr::LocalTee(_)
+            | Instr::GlobalGet(_)
+            | Instr::GlobalSet(_)
+            | Instr::I32Store(_)
+            | Instr::Call(_)
+            | Instr::ReturnCall(_)
+            | Instr::ReturnCallIndirect(_, _)
+            | Instr::End
+            | Instr::MemoryInit(_)
+            | Instr::DataDrop(_)
+            | Instr::MemoryCopy
+            | Instr::MemoryFill
+            | Instr::AtomicFence => &[],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Func {
     ty: FuncType,
+    // From the `name` custom section.
+    name: Option<String>,
+    // From the `name` custom section, by local index, params included.
+    local_names: HashMap<u32, String>,
     locals: Vec<Val>,
-    pub(crate) body: Vec<Instr>,
+    // Undecoded body, only kept around when bodies are parsed lazily. `None`
+    // when the body was decoded eagerly, or there is no code section.
+    raw_body: Option<Vec<u8>>,
+    // Shared with the functions looked up in instances.
+    body: OnceCell<Rc<[Instr]>>,
+}
+
+impl Func {
+    pub(crate) fn ty(&self) -> &FuncType {
+        &self.ty
+    }
+
+    /// The name of the function in the `name` custom section, if any.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The name of the local at local index `idx` in the `name` custom
+    /// section, if any. Params are the first locals.
+    pub fn local_name(&self, idx: u32) -> Option<&str> {
+        self.local_names.get(&idx).map(String::as_str)
+    }
+
+    /// The types of the declared locals, not including the params.
+    pub(crate) fn locals(&self) -> &[Val] {
+        &self.locals
+    }
+
+    pub(crate) fn is_decoded(&self) -> bool {
+        self.body.get().is_some()
+    }
+
+    pub(crate) fn body(&self) -> Result<&[Instr]> {
+        Ok(self.shared_body()?)
+    }
+
+    pub(crate) fn shared_body(&self) -> Result<&Rc<[Instr]>> {
+        if let Some(body) = self.body.get() {
+            return Ok(body);
+        }
+
+        let Some(raw_body) = &self.raw_body else {
+            bail!("function has no body");
+        };
+        let body = Module::parse_instructions(&mut raw_body.as_ref())?;
+        Ok(self.body.get_or_init(|| body.into()))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GlobalType {
+    pub val: Val,
+    pub mutable: bool,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Global {
+    pub(crate) ty: GlobalType,
+    pub(crate) init: Vec<Instr>,
+}
+
+/// Size limits of a table or memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub min: u32,
+    pub max: Option<u32>,
+}
+
+/// Sizes of a linear memory, in pages of [`PAGE_SIZE`] bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryType {
+    pub limits: Limits,
+}
+
+/// Size of a page of linear memory.
+pub const PAGE_SIZE: usize = 65536;
+
+// A 32-bit address space fits this many pages.
+pub(crate) const MAX_PAGES: u32 = 65536;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableType {
+    pub elem: Val,
+    pub limits: Limits,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ImportKind {
+    /// A function with the signature at this type index.
+    Func(u32),
+    Table(TableType),
+    Memory(MemoryType),
+    Global(GlobalType),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+    pub kind: ImportKind,
+}
+
+// The contents of a `name` custom section, by function index.
+#[derive(Default)]
+struct Names {
+    funcs: HashMap<u32, String>,
+    locals: HashMap<u32, HashMap<u32, String>>,
+}
+
+/// The toolchain that produced a module, from its `producers` custom section.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Producers {
+    /// Source languages the module was written in.
+    pub language: Vec<Producer>,
+    /// Tools that processed the module, like compilers and optimizers.
+    pub processed_by: Vec<Producer>,
+    /// SDKs the module was built with.
+    pub sdk: Vec<Producer>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Producer {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DataMode {
+    /// Only copied into a memory by `memory.init`.
+    Passive,
+    /// Copied into memory `memory` at `offset` during instantiation.
+    Active { memory: u32, offset: Vec<Instr> },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Data {
+    pub mode: DataMode,
+    pub init: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportKind {
+    Func,
+    Table,
+    Memory,
+    Global,
+}
+
+impl ExportKind {
+    fn encode(&self) -> u8 {
+        match self {
+            ExportKind::Func => 0x00,
+            ExportKind::Table => 0x01,
+            ExportKind::Memory => 0x02,
+            ExportKind::Global => 0x03,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Export {
     pub(crate) name: String,
-    ty: u64,
-    pub(crate) idx: u64,
+    pub(crate) kind: ExportKind,
+    pub(crate) idx: u32,
+}
+
+/// The version and layer fields following the magic number. Core modules use
+/// version 1, layer 0.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Version {
+    pub version: u16,
+    pub layer: u16,
+}
+
+impl Version {
+    pub const CORE: Version = Version {
+        version: 1,
+        layer: 0,
+    };
+}
+
+/// A section that failed to parse, see [`Module::from_bytes_lenient`].
+#[derive(Debug)]
+pub struct SectionError {
+    pub id: u8,
+    pub error: anyhow::Error,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Section {
+    id: u8,
+    // Byte range of the section's contents in the original buffer, without
+    // the id and size prefix.
+    range: Range<usize>,
+    // Replaces the original contents once the section was modified.
+    encoded: Option<Vec<u8>>,
 }
 
-#[derive(Debug, PartialEq, Default)]
+impl Section {
+    fn contents<'a>(&'a self, bytes: &'a [u8]) -> &'a [u8] {
+        match &self.encoded {
+            Some(encoded) => encoded,
+            None => &bytes[self.range.clone()],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Default)]
 pub struct Module {
+    version: Version,
+    pub(crate) types: Vec<FuncType>,
+    pub imports: Vec<Import>,
+    // Only the functions defined by the module, imported functions come first
+    // in the function index space.
     pub funcs: Vec<Func>,
+    pub memories: Vec<MemoryType>,
+    pub globals: Vec<Global>,
     pub exports: Vec<Export>,
+    pub start: Option<u32>,
+    pub data: Vec<Data>,
+    // Type index of every imported function, by function index. Kept next to
+    // `imports` so that calls don't have to look through them.
+    imported_func_types: Vec<u32>,
+    bytes: Vec<u8>,
+    sections: Vec<Section>,
 }
 
 impl Module {
-    pub fn from_file<T>(_store: &Store, file: T) -> Result<Self>
+    pub fn from_file<T>(store: &Store, file: T) -> Result<Self>
     where
         T: AsRef<Path>,
     {
+        let bytes = std::fs::read(file)?;
+        Self::parse(store, bytes, None)
+    }
+
+    /// Parses a module from its binary encoding, for modules that don't come
+    /// from a file like ones embedded with `include_bytes!`.
+    pub fn from_bytes(store: &Store, bytes: &[u8]) -> Result<Self> {
+        Self::parse(store, bytes.to_vec(), None)
+    }
+
+    /// Parses as much of the module as possible: a section that fails to
+    /// parse is skipped and its error recorded instead of aborting. Only a
+    /// bad header or a truncated section stops the parse.
+    pub fn from_bytes_lenient(store: &Store, bytes: &[u8]) -> Result<(Self, Vec<SectionError>)> {
+        let mut errors = Vec::new();
+        let module = Self::parse(store, bytes.to_vec(), Some(&mut errors))?;
+        Ok((module, errors))
+    }
+
+    // Parses a whole module. When `errors` is given, section errors are
+    // collected there instead of being returned.
+    fn parse(
+        store: &Store,
+        bytes: Vec<u8>,
+        mut errors: Option<&mut Vec<SectionError>>,
+    ) -> Result<Self> {
         let mut module = Self::default();
-        let contents = std::fs::read(file)?;
 
-        let contents: &[u8] = contents.as_ref();
+        let contents: &[u8] = bytes.as_ref();
 
         let mut magic = bytes::Buf::take(contents, 4);
         let mut dst = vec![];
@@ -86,51 +696,529 @@ impl Module {
         let mut dst = vec![];
         dst.put(&mut version);
 
-        if dst != VERSION {
-            bail!("wrong version");
+        if dst.len() != 4 {
+            bail!("truncated version");
+        }
+        module.version = Version {
+            version: u16::from_le_bytes([dst[0], dst[1]]),
+            layer: u16::from_le_bytes([dst[2], dst[3]]),
+        };
+        if module.version != Version::CORE {
+            bail!(
+                "unsupported version {dst:02x?} (version {}, layer {}), only core modules (version 1, layer 0) are supported",
+                module.version.version,
+                module.version.layer
+            );
         }
 
         let mut contents = version.into_inner();
 
-        let mut func_types = Vec::new();
         loop {
             if contents.remaining() == 0 {
                 break;
             }
-            let section = contents.get_u8();
+            let section = Self::read_byte(&mut contents)?;
+            let section_len = leb::read_u32(&mut contents)? as usize;
+            if section_len > contents.remaining() {
+                bail!("section {section} is truncated");
+            }
 
-            match section {
-                0x01 => {
-                    func_types =
-                        Self::parse_type_section(&mut contents).context("parse type section")?
+            let offset = bytes.len() - contents.remaining();
+            module.sections.push(Section {
+                id: section,
+                range: offset..offset + section_len,
+                encoded: None,
+            });
+
+            let (mut payload, rest) = contents.split_at(section_len);
+            contents = rest;
+
+            if let Err(error) = module.parse_section(store, section, &mut payload) {
+                match errors.as_deref_mut() {
+                    Some(errors) => errors.push(SectionError { id: section, error }),
+                    None => return Err(error),
                 }
-                0x03 => {
-                    module.funcs = Self::parse_function_section(&mut contents, func_types.clone())
+            }
+        }
+
+        // The counts of the function and code sections must match, which a
+        // missing code section doesn't
+        if !module.funcs.is_empty() && !module.sections.iter().any(|s| s.id == 0x0A) {
+            let error = anyhow!("function and code section have inconsistent lengths");
+            match errors {
+                Some(errors) => errors.push(SectionError { id: 0x0A, error }),
+                None => return Err(error),
+            }
+        }
+
+        // The name section can come before the functions it names are
+        // declared. Like any custom section, a malformed one doesn't make the
+        // module invalid, it is ignored as a whole
+        let names = module
+            .sections
+            .iter()
+            .find(|s| s.id == 0x00 && custom_section_name(&bytes[s.range.clone()]) == Some("name"))
+            .map(|s| s.range.clone());
+        if let Some(Ok(mut names)) = names.map(|range| Self::parse_name_section(&mut &bytes[range]))
+        {
+            let num_imported = module.num_imported_funcs();
+            for (idx, func) in (num_imported..).zip(module.funcs.iter_mut()) {
+                func.name = names.funcs.remove(&idx);
+                func.local_names = names.locals.remove(&idx).unwrap_or_default();
+            }
+        }
+
+        module.bytes = bytes;
+
+        Ok(module)
+    }
+
+    fn parse_section(&mut self, store: &Store, id: u8, payload: &mut &[u8]) -> Result<()> {
+        match id {
+            0x01 => self.types = Self::parse_type_section(payload).context("parse type section")?,
+            0x02 => {
+                self.imports = Self::parse_import_section(payload, &self.types)
+                    .context("parse import section")?;
+                self.imported_func_types = self
+                    .imports
+                    .iter()
+                    .filter_map(|i| match i.kind {
+                        ImportKind::Func(ty) => Some(ty),
+                        _ => None,
+                    })
+                    .collect();
+            }
+            0x03 => {
+                self.funcs =
+                    Self::parse_function_section(payload, &self.types, store.config.max_functions)
                         .context("parse function section")?
+            }
+            0x05 => {
+                self.memories =
+                    Self::parse_memory_section(payload).context("parse memory section")?
+            }
+            0x06 => {
+                self.globals =
+                    Self::parse_global_section(payload).context("parse global section")?
+            }
+            0x07 => {
+                self.exports = Self::parse_export_section(payload, store.config.max_exports)
+                    .context("parse export section")?
+            }
+            0x08 => self.start = Some(leb::read_u32(payload).context("parse start section")?),
+            0x0A => {
+                let first_idx = self.num_imported_funcs();
+                Self::parse_code_section(
+                    payload,
+                    &mut self.funcs,
+                    first_idx,
+                    store.config.lazy_function_bodies,
+                )
+                .context("parse code section")?
+            }
+            0x0B => self.data = Self::parse_data_section(payload).context("parse data section")?,
+            // Custom sections and the sections that aren't decoded, like the
+            // table one, are only kept as raw bytes in `sections`. The name
+            // section is parsed once all the sections are
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Returns the name and signature of every exported function.
+    pub fn exported_functions(&self) -> Vec<(String, FuncType)> {
+        self.exports
+            .iter()
+            .filter(|e| e.kind == ExportKind::Func)
+            .filter_map(|e| Some((e.name.clone(), self.func_type(e.idx)?.clone())))
+            .collect()
+    }
+
+    /// Number of imported functions, which take the lowest function indices.
+    pub fn num_imported_funcs(&self) -> u32 {
+        self.imported_func_types.len() as u32
+    }
+
+    /// The function defined by the module at function index `idx`, `None` if
+    /// the index is out of range or refers to an imported function.
+    pub(crate) fn defined_func(&self, idx: u32) -> Option<&Func> {
+        let defined = idx.checked_sub(self.num_imported_funcs())?;
+        self.funcs.get(defined as usize)
+    }
+
+    /// The signature of the function at function index `idx`, imported or
+    /// not.
+    pub fn func_type(&self, idx: u32) -> Option<&FuncType> {
+        match self.imported_func_types.get(idx as usize) {
+            Some(ty) => self.types.get(*ty as usize),
+            None => Some(self.defined_func(idx)?.ty()),
+        }
+    }
+
+    /// The type of the global at global index `idx`, imported or not.
+    pub(crate) fn global_type(&self, idx: u32) -> Option<&GlobalType> {
+        let mut imported = self.imports.iter().filter_map(|i| match &i.kind {
+            ImportKind::Global(ty) => Some(ty),
+            _ => None,
+        });
+        let num_imported = imported.clone().count();
+        match imported.nth(idx as usize) {
+            Some(ty) => Some(ty),
+            None => Some(&self.globals.get(idx as usize - num_imported)?.ty),
+        }
+    }
+
+    /// The functions that can run when the functions at indices `roots` are
+    /// called, roots included. Element segments aren't parsed so a body
+    /// doing an indirect call, or that can't be decoded, makes every function
+    /// reachable.
+    pub fn reachable_functions(&self, roots: &[u32]) -> HashSet<u32> {
+        let num_funcs = self.num_imported_funcs() + self.funcs.len() as u32;
+        let mut reachable = HashSet::new();
+        let mut worklist = roots.to_vec();
+
+        while let Some(idx) = worklist.pop() {
+            if idx >= num_funcs || !reachable.insert(idx) {
+                continue;
+            }
+            // Imported functions can't call back into the module
+            let Some(func) = self.defined_func(idx) else {
+                continue;
+            };
+
+            let Ok(body) = func.body() else {
+                return (0..num_funcs).collect();
+            };
+            for instr in body {
+                match instr {
+                    Instr::Call(callee) | Instr::ReturnCall(callee) => worklist.push(*callee),
+                    Instr::ReturnCallIndirect(_, _) => return (0..num_funcs).collect(),
+                    _ => {}
                 }
-                0x07 => {
-                    module.exports =
-                        Self::parse_export_section(&mut contents).context("parse export section")?
-                }
-                0x0A => Self::parse_code_section(&mut contents, &mut module)
-                    .context("parse code section")?,
-                _ => {
-                    let section_len = leb128::read::unsigned(&mut contents)?;
-                    let mut t = bytes::Buf::take(contents, section_len as usize);
-                    let mut dst = vec![];
-                    dst.put(&mut t);
-                    contents = t.into_inner();
-                    println!("Unknown section id {section}, skipping");
+            }
+        }
+
+        reachable
+    }
+
+    /// The imported functions some body calls, by function index. Like
+    /// [`Module::reachable_functions`], a body doing an indirect call, or
+    /// that can't be decoded, counts as calling every import.
+    pub fn called_imports(&self) -> HashSet<u32> {
+        let num_imported = self.num_imported_funcs();
+        let mut called = HashSet::new();
+
+        for func in &self.funcs {
+            let Ok(body) = func.body() else {
+                return (0..num_imported).collect();
+            };
+            for instr in body {
+                match instr {
+                    Instr::Call(callee) | Instr::ReturnCall(callee) if *callee < num_imported => {
+                        called.insert(*callee);
+                    }
+                    Instr::ReturnCallIndirect(_, _) => return (0..num_imported).collect(),
+                    _ => {}
                 }
             }
         }
 
-        Ok(module)
+        called
+    }
+
+    // Describes the function at function index `idx` for error messages,
+    // with its name when the module has one for it.
+    pub(crate) fn describe_func(&self, idx: u32) -> String {
+        match self.defined_func(idx).and_then(Func::name) {
+            Some(name) => format!("function {idx} ({name})"),
+            None => format!("function {idx}"),
+        }
+    }
+
+    /// A 64-bit FNV-1a hash of [`Module::to_bytes`]. It only depends on the
+    /// contents, so it is stable across runs and can be used as a cache key.
+    pub fn content_hash(&self) -> u64 {
+        const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const PRIME: u64 = 0x100000001b3;
+
+        self.to_bytes().iter().fold(OFFSET_BASIS, |hash, b| {
+            (hash ^ *b as u64).wrapping_mul(PRIME)
+        })
+    }
+
+    /// Returns the raw contents of the first section with the given id, as
+    /// they appear in the original module bytes unless the module was
+    /// modified since.
+    pub fn section_bytes(&self, id: u8) -> Option<&[u8]> {
+        self.sections
+            .iter()
+            .find(|s| s.id == id)
+            .map(|s| s.contents(&self.bytes))
+    }
+
+    // Numbers of params and results of a block of type `ty`, `None` if it
+    // refers to an unknown type.
+    pub(crate) fn block_arity(&self, ty: &BlockType) -> Option<(usize, usize)> {
+        match ty {
+            BlockType::Empty => Some((0, 0)),
+            BlockType::Value(_) => Some((0, 1)),
+            BlockType::Func(idx) => self
+                .types
+                .get(*idx as usize)
+                .map(|ty| (ty.params.len(), ty.results.len())),
+        }
+    }
+
+    // The params and results of a block of type `ty`, `None` if it refers to
+    // an unknown type.
+    pub(crate) fn block_signature(&self, ty: &BlockType) -> Option<FuncType> {
+        match ty {
+            BlockType::Empty => Some(FuncType::default()),
+            BlockType::Value(val) => Some(FuncType::new(vec![], vec![val.clone()])),
+            BlockType::Func(idx) => self.types.get(*idx as usize).cloned(),
+        }
+    }
+
+    /// The function signatures of the type section.
+    pub fn types(&self) -> &[FuncType] {
+        &self.types
+    }
+
+    /// Returns the index of the type `ty`, adding it to the type section if
+    /// the module doesn't have an identical one yet.
+    pub fn add_type(&mut self, ty: FuncType) -> u32 {
+        if let Some(idx) = self.types.iter().position(|t| *t == ty) {
+            return idx as u32;
+        }
+
+        self.types.push(ty);
+        self.encode_type_section();
+        self.types.len() as u32 - 1
+    }
+
+    fn encode_type_section(&mut self) {
+        let mut contents = Vec::new();
+        leb::write_u32(&mut contents, self.types.len() as u32);
+        for ty in &self.types {
+            contents.push(0x60);
+            for vals in [&ty.params, &ty.results] {
+                leb::write_u32(&mut contents, vals.len() as u32);
+                contents.extend(vals.iter().map(Val::encode));
+            }
+        }
+
+        match self.sections.iter_mut().find(|s| s.id == 0x01) {
+            Some(section) => section.encoded = Some(contents),
+            None => {
+                // The type section comes before every other known section
+                let pos = self.sections.iter().position(|s| s.id != 0x00);
+                self.sections.insert(
+                    pos.unwrap_or(self.sections.len()),
+                    Section {
+                        id: 0x01,
+                        range: 0..0,
+                        encoded: Some(contents),
+                    },
+                );
+            }
+        }
+    }
+
+    /// Renames the export `old` to `new`, the change is kept by `to_bytes`.
+    pub fn rename_export(&mut self, old: &str, new: &str) -> Result<()> {
+        if self.exports.iter().any(|e| e.name == new) {
+            bail!("export {new} already exists");
+        }
+        let Some(export) = self.exports.iter_mut().find(|e| e.name == old) else {
+            bail!("unknown export {old}");
+        };
+
+        export.name = new.to_string();
+        self.encode_export_section();
+        Ok(())
+    }
+
+    fn encode_export_section(&mut self) {
+        let mut contents = Vec::new();
+        leb::write_u32(&mut contents, self.exports.len() as u32);
+        for export in &self.exports {
+            leb::write_u32(&mut contents, export.name.len() as u32);
+            contents.extend_from_slice(export.name.as_bytes());
+            contents.push(export.kind.encode());
+            leb::write_u32(&mut contents, export.idx);
+        }
+
+        // Only called once there are exports, so the section exists
+        if let Some(section) = self.sections.iter_mut().find(|s| s.id == 0x07) {
+            section.encoded = Some(contents);
+        }
+    }
+
+    /// Replaces the function bodies with the ones of `contents`, the contents
+    /// of a code section coming from another copy of the module. It must have
+    /// a body for every function. The module is left as is on error.
+    pub fn merge_code_section(&mut self, contents: &[u8]) -> Result<()> {
+        let mut funcs = self.funcs.clone();
+        let mut rest = contents;
+        Self::parse_code_section(&mut rest, &mut funcs, self.num_imported_funcs(), false)
+            .context("parse code section")?;
+        if !rest.is_empty() {
+            bail!("code section has {} bytes after the bodies", rest.len());
+        }
+
+        self.funcs = funcs;
+        match self.sections.iter_mut().find(|s| s.id == 0x0A) {
+            Some(section) => section.encoded = Some(contents.to_vec()),
+            None => {
+                // The code section only comes before the data section
+                let pos = self.sections.iter().position(|s| s.id == 0x0B);
+                self.sections.insert(
+                    pos.unwrap_or(self.sections.len()),
+                    Section {
+                        id: 0x0A,
+                        range: 0..0,
+                        encoded: Some(contents.to_vec()),
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Encodes the module back into the binary format, with the sections in
+    /// their original order, minus the ones that were stripped.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Magic number and version, a default module gets a core module's
+        let mut result = match self.bytes.get(..8) {
+            Some(header) => header.to_vec(),
+            None => b"\0asm\x01\0\0\0".to_vec(),
+        };
+
+        for section in &self.sections {
+            let contents = section.contents(&self.bytes);
+            result.push(section.id);
+            leb::write_u32(&mut result, contents.len() as u32);
+            result.extend_from_slice(contents);
+        }
+
+        result
+    }
+
+    /// Removes every custom section, they are left out of
+    /// [`Module::to_bytes`].
+    pub fn strip_custom_sections(&mut self) {
+        self.sections.retain(|s| s.id != 0x00);
+    }
+
+    /// Removes the custom sections called `name`.
+    pub fn strip_custom_section(&mut self, name: &str) {
+        let bytes = &self.bytes;
+        self.sections
+            .retain(|s| s.id != 0x00 || custom_section_name(s.contents(bytes)) != Some(name));
+    }
+
+    /// The decoded `producers` custom section, `None` if the module doesn't
+    /// have one or it is malformed.
+    pub fn producers(&self) -> Option<Producers> {
+        let contents = self
+            .sections
+            .iter()
+            .filter(|s| s.id == 0x00)
+            .map(|s| s.contents(&self.bytes))
+            .find(|contents| custom_section_name(contents) == Some("producers"))?;
+        Self::parse_producers_section(contents).ok()
+    }
+
+    fn parse_producers_section(mut contents: &[u8]) -> Result<Producers> {
+        let contents = &mut contents;
+        // Section name
+        Self::parse_name(contents)?;
+
+        let mut producers = Producers::default();
+        let num_fields = leb::read_u32(contents)?;
+        for _ in 0..num_fields {
+            let field = Self::parse_name(contents)?;
+            let num_values = leb::read_u32(contents)?;
+            let mut values = Vec::new();
+            for _ in 0..num_values {
+                values.push(Producer {
+                    name: Self::parse_name(contents)?,
+                    version: Self::parse_name(contents)?,
+                });
+            }
+
+            match field.as_str() {
+                "language" => producers.language = values,
+                "processed-by" => producers.processed_by = values,
+                "sdk" => producers.sdk = values,
+                // Fields added by later versions of the format
+                _ => {}
+            }
+        }
+
+        Ok(producers)
+    }
+
+    /// Evaluates a constant expression in the context of this module. A
+    /// `global.get` produces the initial value of the global, only immutable
+    /// globals can be read, and imported globals can't as their values aren't
+    /// known: see [`Module::eval_const_expr_with_imports`].
+    pub fn eval_const_expr(&self, expr: &[Instr]) -> Result<Value> {
+        self.eval_const_expr_with_imports(expr, &[])
+    }
+
+    /// Like [`Module::eval_const_expr`], with `imported` the values of the
+    /// imported globals, in the order of their imports.
+    pub fn eval_const_expr_with_imports(
+        &self,
+        expr: &[Instr],
+        imported: &[Value],
+    ) -> Result<Value> {
+        self.eval_const_expr_before(expr, imported, self.globals.len())
+    }
+
+    // Only the first `visible` defined globals may be referenced, which also
+    // keeps a global from referring to itself.
+    fn eval_const_expr_before(
+        &self,
+        expr: &[Instr],
+        imported: &[Value],
+        visible: usize,
+    ) -> Result<Value> {
+        let num_imported = self
+            .imports
+            .iter()
+            .filter(|i| matches!(i.kind, ImportKind::Global(_)))
+            .count();
+
+        eval_const_expr(expr, |idx| {
+            let Some(ty) = self.global_type(idx) else {
+                bail!("unknown global {idx}");
+            };
+            if ty.mutable {
+                bail!("global {idx} is mutable, it can't be used in a constant expression");
+            }
+            match (idx as usize).checked_sub(num_imported) {
+                None => match imported.get(idx as usize) {
+                    Some(value) if ty.val.accepts(value) => Ok(*value),
+                    Some(value) => bail!("global {idx} has type {:?}, got {value:?}", ty.val),
+                    None => bail!("global {idx} is imported, its value isn't known"),
+                },
+                Some(defined) if defined < visible => self
+                    .eval_const_expr_before(&self.globals[defined].init, imported, defined)
+                    .with_context(|| format!("initialize global {idx}")),
+                Some(_) => bail!("unknown global {idx}"),
+            }
+        })
     }
 
-    fn parse_type_section(mut contents: &mut &[u8]) -> Result<Vec<FuncType>> {
-        let _section_len = leb128::read::unsigned(&mut contents)?;
-        let types_len = leb128::read::unsigned(&mut contents)?;
+    fn parse_type_section(contents: &mut &[u8]) -> Result<Vec<FuncType>> {
+        let types_len = leb::read_u32(contents)?;
 
         let mut result = Vec::new();
 
@@ -138,17 +1226,28 @@ impl Module {
             let mut func_type = FuncType::default();
 
             // 0x60, start of functype
-            let start = contents.get_u8();
-            if start != 0x60 {
-                bail!("malformed module, expected start of functype (0x60), got {start}");
+            match Self::read_byte(contents)? {
+                0x60 => {}
+                // Rec groups, sub types, struct and array types from the GC
+                // proposal
+                0x4E | 0x50 | 0x4F | 0x5F | 0x5E => bail!("unsupported: GC types"),
+                start => {
+                    bail!("malformed module, expected start of functype (0x60), got {start}")
+                }
             }
 
-            let params_len = leb128::read::unsigned(&mut contents)?;
+            let params_len = leb::read_u32(contents)?;
+            if params_len > MAX_PARAMS {
+                bail!("too many params ({params_len}), the maximum is {MAX_PARAMS}");
+            }
             for _ in 0..params_len {
                 func_type.params.push(Self::parse_val(contents)?);
             }
 
-            let results_len = leb128::read::unsigned(contents)?;
+            let results_len = leb::read_u32(contents)?;
+            if results_len > MAX_RESULTS {
+                bail!("too many results ({results_len}), the maximum is {MAX_RESULTS}");
+            }
             for _ in 0..results_len {
                 func_type.results.push(Self::parse_val(contents)?);
             }
@@ -159,21 +1258,72 @@ impl Module {
         Ok(result)
     }
 
+    // Only the function and local names subsections are used, names of
+    // imported functions are dropped.
+    // Parses the function and local names of a `name` section, by
+    // function index.
+    fn parse_name_section(contents: &mut &[u8]) -> Result<Names> {
+        // Section name
+        Self::parse_name(contents)?;
+
+        let mut names = Names::default();
+        while !contents.is_empty() {
+            let id = Self::read_byte(contents)?;
+            let len = leb::read_u32(contents)? as usize;
+            if len > contents.len() {
+                bail!("name subsection is truncated");
+            }
+            let (mut subsection, rest) = contents.split_at(len);
+            *contents = rest;
+            if id != 1 && id != 2 {
+                continue;
+            }
+
+            let num_funcs = leb::read_u32(&mut subsection)?;
+            for _ in 0..num_funcs {
+                let idx = leb::read_u32(&mut subsection)?;
+                if id == 1 {
+                    names.funcs.insert(idx, Self::parse_name(&mut subsection)?);
+                    continue;
+                }
+
+                // The names of the function's locals
+                let mut local_names = HashMap::new();
+                let num_locals = leb::read_u32(&mut subsection)?;
+                for _ in 0..num_locals {
+                    let local = leb::read_u32(&mut subsection)?;
+                    local_names.insert(local, Self::parse_name(&mut subsection)?);
+                }
+                names.locals.insert(idx, local_names);
+            }
+        }
+
+        Ok(names)
+    }
+
     fn parse_function_section(
-        mut contents: &mut &[u8],
-        func_types: Vec<FuncType>,
+        contents: &mut &[u8],
+        func_types: &[FuncType],
+        max_functions: Option<u64>,
     ) -> Result<Vec<Func>> {
-        let _section_len = leb128::read::unsigned(&mut contents)?;
+        let function_len = leb::read_u32(contents)?;
+        if let Some(max) = max_functions {
+            if function_len as u64 > max {
+                bail!("module declares {function_len} functions, the maximum is {max}");
+            }
+        }
 
-        let function_len = leb128::read::unsigned(&mut contents)?;
         let mut result = Vec::new();
         for _ in 0..function_len {
-            let idx = leb128::read::unsigned(&mut contents)?;
+            let idx = leb::read_u32(contents)?;
             if let Some(ty) = func_types.get(idx as usize) {
                 result.push(Func {
                     ty: ty.clone(),
+                    name: None,
+                    local_names: HashMap::new(),
                     locals: Vec::new(),
-                    body: Vec::new(),
+                    raw_body: None,
+                    body: OnceCell::new(),
                 });
             } else {
                 bail!("Unable to find function type {}", idx);
@@ -183,14 +1333,168 @@ impl Module {
         Ok(result)
     }
 
-    fn parse_export_section(mut contents: &mut &[u8]) -> Result<Vec<Export>> {
+    fn parse_global_section(contents: &mut &[u8]) -> Result<Vec<Global>> {
+        let num_globals = leb::read_u32(contents)?;
+
         let mut result = Vec::new();
+        for _ in 0..num_globals {
+            let ty = Self::parse_global_type(contents)?;
+            let init = Self::parse_const_expr(contents).context("parse global initializer")?;
+
+            result.push(Global { ty, init });
+        }
+
+        Ok(result)
+    }
+
+    fn parse_global_type(contents: &mut &[u8]) -> Result<GlobalType> {
+        let val = Self::parse_val(contents)?;
+        let mutable = match Self::read_byte(contents)? {
+            0x00 => false,
+            0x01 => true,
+            m => bail!("malformed global mutability {m}"),
+        };
+
+        Ok(GlobalType { val, mutable })
+    }
 
-        let _section_len = leb128::read::unsigned(&mut contents)?;
-        let num_exports = leb128::read::unsigned(&mut contents)?;
+    fn parse_import_section(contents: &mut &[u8], func_types: &[FuncType]) -> Result<Vec<Import>> {
+        let num_imports = leb::read_u32(contents)?;
+
+        let mut result = Vec::new();
+        for _ in 0..num_imports {
+            let module = Self::parse_name(contents)?;
+            let name = Self::parse_name(contents)?;
+            let kind = match Self::read_byte(contents)? {
+                0x00 => {
+                    let idx = leb::read_u32(contents)?;
+                    if func_types.get(idx as usize).is_none() {
+                        bail!("Unable to find function type {}", idx);
+                    }
+                    ImportKind::Func(idx)
+                }
+                0x01 => ImportKind::Table(TableType {
+                    elem: Self::parse_val(contents)?,
+                    limits: Self::parse_limits(contents)?,
+                }),
+                0x02 => ImportKind::Memory(Self::parse_memory_type(contents)?),
+                0x03 => ImportKind::Global(Self::parse_global_type(contents)?),
+                k => bail!("unknown import kind {k}"),
+            };
+
+            result.push(Import { module, name, kind });
+        }
+
+        Ok(result)
+    }
+
+    fn parse_data_section(contents: &mut &[u8]) -> Result<Vec<Data>> {
+        let num_data = leb::read_u32(contents)?;
+
+        let mut result = Vec::new();
+        for _ in 0..num_data {
+            let mode = match leb::read_u32(contents)? {
+                0 => DataMode::Active {
+                    memory: 0,
+                    offset: Self::parse_const_expr(contents)?,
+                },
+                1 => DataMode::Passive,
+                2 => DataMode::Active {
+                    memory: leb::read_u32(contents)?,
+                    offset: Self::parse_const_expr(contents)?,
+                },
+                flags => bail!("unknown data segment flags {flags}"),
+            };
+
+            let len = leb::read_u32(contents)? as usize;
+            if len > contents.len() {
+                bail!("data segment is truncated");
+            }
+            let (init, rest) = contents.split_at(len);
+            *contents = rest;
+
+            result.push(Data {
+                mode,
+                init: init.to_vec(),
+            });
+        }
+
+        Ok(result)
+    }
+
+    fn parse_memory_section(contents: &mut &[u8]) -> Result<Vec<MemoryType>> {
+        let num_memories = leb::read_u32(contents)?;
+
+        let mut result = Vec::new();
+        for _ in 0..num_memories {
+            result.push(Self::parse_memory_type(contents)?);
+        }
+
+        Ok(result)
+    }
+
+    fn parse_memory_type(contents: &mut &[u8]) -> Result<MemoryType> {
+        let limits = Self::parse_limits(contents)?;
+        if limits.min > MAX_PAGES || limits.max.is_some_and(|max| max > MAX_PAGES) {
+            bail!("memory size must be at most {MAX_PAGES} pages");
+        }
+        if limits.max.is_some_and(|max| max < limits.min) {
+            bail!("memory maximum size is smaller than its minimum");
+        }
+
+        Ok(MemoryType { limits })
+    }
+
+    fn parse_limits(contents: &mut &[u8]) -> Result<Limits> {
+        let limits = match Self::read_byte(contents)? {
+            0x00 => Limits {
+                min: leb::read_u32(contents)?,
+                max: None,
+            },
+            0x01 => Limits {
+                min: leb::read_u32(contents)?,
+                max: Some(leb::read_u32(contents)?),
+            },
+            flags => bail!("unsupported limits flags {flags:#04x}"),
+        };
+
+        Ok(limits)
+    }
+
+    fn read_byte(contents: &mut &[u8]) -> Result<u8> {
+        let Some((byte, rest)) = contents.split_first() else {
+            bail!("unexpected end of section");
+        };
+        *contents = rest;
+        Ok(*byte)
+    }
+
+    fn parse_name(contents: &mut &[u8]) -> Result<String> {
+        let len = leb::read_u32(contents)? as usize;
+        if len > contents.len() {
+            bail!("name is truncated");
+        }
+
+        let (name, rest) = contents.split_at(len);
+        *contents = rest;
+        Ok(String::from_utf8(name.to_vec())?)
+    }
+
+    fn parse_export_section(
+        mut contents: &mut &[u8],
+        max_exports: Option<u64>,
+    ) -> Result<Vec<Export>> {
+        let mut result = Vec::new();
+
+        let num_exports = leb::read_u32(contents)?;
+        if let Some(max) = max_exports {
+            if num_exports as u64 > max {
+                bail!("module declares {num_exports} exports, the maximum is {max}");
+            }
+        }
 
         for _ in 0..num_exports {
-            let n = leb128::read::unsigned(&mut contents)?;
+            let n = leb::read_u32(contents)?;
 
             let mut name = bytes::Buf::take(contents, n as usize);
             let mut n = vec![];
@@ -199,48 +1503,78 @@ impl Module {
             contents = name.into_inner();
 
             let name = String::from_utf8(n)?;
-            let ty = leb128::read::unsigned(&mut contents)?;
-            let idx = leb128::read::unsigned(&mut contents)?;
+            let kind = match leb::read_u32(contents)? {
+                0x00 => ExportKind::Func,
+                0x01 => ExportKind::Table,
+                0x02 => ExportKind::Memory,
+                0x03 => ExportKind::Global,
+                k => bail!("unknown export kind {k}"),
+            };
+            let idx = leb::read_u32(contents)?;
 
-            result.push(Export { name, ty, idx })
+            result.push(Export { name, kind, idx })
         }
 
         Ok(result)
     }
 
-    fn parse_code_section(mut contents: &mut &[u8], module: &mut Module) -> Result<()> {
-        let _section_len = leb128::read::unsigned(&mut contents)?;
+    // `first_idx` is the function index of the first body, after the
+    // imported functions.
+    fn parse_code_section(
+        mut contents: &mut &[u8],
+        funcs: &mut [Func],
+        first_idx: u32,
+        lazy: bool,
+    ) -> Result<()> {
+        let n = leb::read_u32(contents)?;
+        if n as usize != funcs.len() {
+            bail!(
+                "code section has {n} bodies but the function section declares {} functions",
+                funcs.len()
+            );
+        }
 
-        let n = leb128::read::unsigned(&mut contents)?;
-        for i in 0..n {
-            let mut f = module.funcs.get_mut(i as usize).unwrap();
+        for (idx, f) in (first_idx..).zip(funcs.iter_mut()) {
+            let func_len = leb::read_u32(contents)?;
 
-            let func_len = leb128::read::unsigned(&mut contents)?;
+            // The size covers both the locals and the instructions.
+            let mut ne = bytes::Buf::take(contents, func_len as usize);
+            let mut b = vec![];
+            b.put(&mut ne);
+            contents = ne.into_inner();
+            if b.len() != func_len as usize {
+                bail!("function body is truncated");
+            }
 
-            let num_locals = leb128::read::unsigned(&mut contents)?;
-            let er = func_len - num_locals - 1;
+            let mut body: &[u8] = b.as_ref();
+            let num_locals = leb::read_u32(&mut body)?;
             let mut locals = Vec::new();
+            let mut total: u32 = 0;
             for _ in 0..num_locals {
-                let n = leb128::read::unsigned(&mut contents)?;
-                let val = Self::parse_val(contents).context("parse local")?;
-                for _ in 0..n {
-                    locals.push(val.clone());
-                }
+                let n = leb::read_u32(&mut body)?;
+                let val = Self::parse_val(&mut body).context("parse local")?;
+                total = match total.checked_add(n) {
+                    Some(total) if total <= MAX_LOCALS => total,
+                    _ => bail!(
+                        "function {idx} declares too many locals, the maximum is {MAX_LOCALS}"
+                    ),
+                };
+                locals.extend(std::iter::repeat_n(val, n as usize));
             }
 
-            let mut ne = bytes::Buf::take(contents, er as usize);
-            let mut b = vec![];
-            b.put(&mut ne);
             f.locals = locals;
-            f.body = Self::parse_instructions(&mut b.as_ref())?;
-            contents = ne.into_inner();
+            if lazy {
+                f.raw_body = Some(body.to_vec());
+            } else {
+                f.body = OnceCell::from(Rc::from(Self::parse_instructions(&mut body)?));
+            }
         }
 
         Ok(())
     }
 
     fn parse_val(contents: &mut &[u8]) -> Result<Val> {
-        let n = contents.get_u8();
+        let n = Self::read_byte(contents)?;
 
         match n {
             0x7F => Ok(Val::I32),
@@ -254,42 +1588,1019 @@ impl Module {
         }
     }
 
-    fn parse_instructions(mut contents: &mut &[u8]) -> Result<Vec<Instr>> {
+    fn parse_block_type(contents: &mut &[u8]) -> Result<BlockType> {
+        match contents.first() {
+            Some(0x40) => {
+                contents.advance(1);
+                Ok(BlockType::Empty)
+            }
+            Some(0x6F..=0x7F) => Ok(BlockType::Value(Self::parse_val(contents)?)),
+            // Type indices are encoded as positive signed 33-bit integers
+            _ => match u32::try_from(leb::read_i64(contents)?) {
+                Ok(idx) => Ok(BlockType::Func(idx)),
+                Err(_) => bail!("invalid block type"),
+            },
+        }
+    }
+
+    fn parse_instructions(contents: &mut &[u8]) -> Result<Vec<Instr>> {
         let mut result = Vec::new();
+        let len = contents.remaining();
 
         loop {
             if contents.remaining() == 0 {
                 break;
             }
-            let opcode = contents.get_u8();
-
-            let instr = match opcode {
-                0x00 => continue,
-                0x20 => Instr::LocalGet(leb128::read::unsigned(&mut contents)? as u32),
-                0x28 => Instr::LoadI32(leb128::read::signed(&mut contents)? as i32),
-                0x44 => {
-                    let mut name = bytes::Buf::take(contents, 8);
-                    let mut n: [u8; 8] = [0; 8];
-                    name.copy_to_slice(&mut n);
-                    contents = name.into_inner();
-                    Instr::ConstF64(f64::from_le_bytes(n))
-                }
 
-                0x6A => Instr::I32Add,
-                0x6C => Instr::I32Mul,
-                0x10 => Instr::Call(leb128::read::unsigned(&mut contents)? as u32),
-                0x80 => Instr::DivI32U,
-                0x0B => Instr::End,
+            let offset = len - contents.remaining();
+            result.push(Self::parse_instruction(contents, offset)?);
+        }
 
-                _ => {
-                    // println!("Unknown opcode {opcode:#x}");
-                    continue;
-                }
-            };
+        Ok(result)
+    }
+
+    fn parse_memarg(contents: &mut &[u8]) -> Result<MemArg> {
+        Ok(MemArg {
+            align: leb::read_u32(contents)?,
+            offset: leb::read_u32(contents)?,
+        })
+    }
 
+    // Reads instructions up to and including the `End` closing a constant
+    // expression.
+    fn parse_const_expr(contents: &mut &[u8]) -> Result<Vec<Instr>> {
+        let mut result = Vec::new();
+        let len = contents.remaining();
+
+        loop {
+            if contents.remaining() == 0 {
+                bail!("unterminated constant expression");
+            }
+
+            let offset = len - contents.remaining();
+            let instr = Self::parse_instruction(contents, offset)?;
+            let end = instr == Instr::End;
             result.push(instr);
+            if end {
+                break;
+            }
         }
 
         Ok(result)
     }
+
+    // Decodes the next instruction, failing on opcodes that aren't supported.
+    // `offset` is the position of the instruction in the body, for errors.
+    fn parse_instruction(contents: &mut &[u8], offset: usize) -> Result<Instr> {
+        let opcode = Self::read_byte(contents)?;
+
+        let instr = match opcode {
+            0x00 => Instr::Unreachable,
+            0x01 => Instr::Nop,
+            0x02 => Instr::Block(Self::parse_block_type(contents)?),
+            0x03 => Instr::Loop(Self::parse_block_type(contents)?),
+            0x04 => Instr::If(Self::parse_block_type(contents)?),
+            0x05 => Instr::Else,
+            0x0C => Instr::Br(leb::read_u32(contents)?),
+            0x0D => Instr::BrIf(leb::read_u32(contents)?),
+            0x0F => Instr::Return,
+            0x20 => Instr::LocalGet(leb::read_u32(contents)?),
+            0x21 => Instr::LocalSet(leb::read_u32(contents)?),
+            0x22 => Instr::LocalTee(leb::read_u32(contents)?),
+            0x23 => Instr::GlobalGet(leb::read_u32(contents)?),
+            0x24 => Instr::GlobalSet(leb::read_u32(contents)?),
+            0x28 => Instr::I32Load(Self::parse_memarg(contents)?),
+            0x2D => Instr::I32Load8U(Self::parse_memarg(contents)?),
+            0x36 => Instr::I32Store(Self::parse_memarg(contents)?),
+            0x3F => {
+                // Reserved memory index
+                Self::read_byte(contents)?;
+                Instr::MemorySize
+            }
+            0x40 => {
+                // Reserved memory index
+                Self::read_byte(contents)?;
+                Instr::MemoryGrow
+            }
+            0x41 => Instr::ConstI32(leb::read_i32(contents)?),
+            0x42 => Instr::ConstI64(leb::read_i64(contents)?),
+            0x44 => {
+                let Some((n, rest)) = contents.split_first_chunk::<8>() else {
+                    bail!("f64.const is truncated");
+                };
+                *contents = rest;
+                Instr::ConstF64(f64::from_le_bytes(*n))
+            }
+
+            0x45 => Instr::I32Eqz,
+            0x46 => Instr::I32Eq,
+            0x47 => Instr::I32Ne,
+            0x48 => Instr::I32LtS,
+            0x49 => Instr::I32LtU,
+            0x4A => Instr::I32GtS,
+            0x4B => Instr::I32GtU,
+            0x4C => Instr::I32LeS,
+            0x4D => Instr::I32LeU,
+            0x4E => Instr::I32GeS,
+            0x4F => Instr::I32GeU,
+            0x6A => Instr::I32Add,
+            0x6C => Instr::I32Mul,
+            0x6B => Instr::I32Sub,
+            0x6D => Instr::I32DivS,
+            0x6F => Instr::I32RemS,
+            0x70 => Instr::I32RemU,
+            0x71 => Instr::I32And,
+            0x72 => Instr::I32Or,
+            0x73 => Instr::I32Xor,
+            0x74 => Instr::I32Shl,
+            0x75 => Instr::I32ShrS,
+            0x76 => Instr::I32ShrU,
+            0x7C => Instr::I64Add,
+            0x7D => Instr::I64Sub,
+            0x7E => Instr::I64Mul,
+            0xA0 => Instr::F64Add,
+            0xA1 => Instr::F64Sub,
+            0xA2 => Instr::F64Mul,
+            0xA3 => Instr::F64Div,
+            0x10 => Instr::Call(leb::read_u32(contents)?),
+            0x12 => Instr::ReturnCall(leb::read_u32(contents)?),
+            0x13 => {
+                let type_idx = leb::read_u32(contents)?;
+                let table_idx = leb::read_u32(contents)?;
+                Instr::ReturnCallIndirect(type_idx, table_idx)
+            }
+            0x80 => Instr::DivI32U,
+            0xB6 => Instr::F32DemoteF64,
+            0xBB => Instr::F64PromoteF32,
+            0xBC => Instr::I32ReinterpretF32,
+            0xBD => Instr::I64ReinterpretF64,
+            0xBE => Instr::F32ReinterpretI32,
+            0xBF => Instr::F64ReinterpretI64,
+            0x0B => Instr::End,
+
+            0xFC..=0xFE => {
+                let sub_opcode = leb::read_u32(contents)?;
+                return Self::parse_prefixed_instruction(opcode, sub_opcode, contents, offset);
+            }
+
+            _ => bail!("unknown opcode {opcode:#x} at offset {offset}"),
+        };
+
+        Ok(instr)
+    }
+
+    // Decodes an instruction encoded as a prefix byte followed by a LEB128
+    // sub-opcode.
+    fn parse_prefixed_instruction(
+        prefix: u8,
+        sub_opcode: u32,
+        contents: &mut &[u8],
+        offset: usize,
+    ) -> Result<Instr> {
+        let instr = match (prefix, sub_opcode) {
+            (0xFC, 8) => {
+                let data_idx = leb::read_u32(contents)?;
+                // Reserved memory index
+                Self::read_byte(contents)?;
+                Instr::MemoryInit(data_idx)
+            }
+            (0xFC, 9) => Instr::DataDrop(leb::read_u32(contents)?),
+            (0xFC, 10) => {
+                // Reserved destination and source memory indices
+                Self::read_byte(contents)?;
+                Self::read_byte(contents)?;
+                Instr::MemoryCopy
+            }
+            (0xFC, 11) => {
+                // Reserved memory index
+                Self::read_byte(contents)?;
+                Instr::MemoryFill
+            }
+            (0xFE, 3) => {
+                if Self::read_byte(contents)? != 0 {
+                    bail!("malformed atomic.fence at offset {offset}");
+                }
+                Instr::AtomicFence
+            }
+            _ => bail!("unknown opcode {prefix:#x} {sub_opcode} at offset {offset}"),
+        };
+
+        Ok(instr)
+    }
+}
+
+// The name a custom section starts with, `None` if it is malformed.
+fn custom_section_name(mut contents: &[u8]) -> Option<&str> {
+    let len = leb::read_u32(&mut contents).ok()? as usize;
+    std::str::from_utf8(contents.get(..len)?).ok()
+}
+
+// Evaluates a constant expression, `global_get` produces the value of a
+// global.
+pub(crate) fn eval_const_expr(
+    expr: &[Instr],
+    mut global_get: impl FnMut(u32) -> Result<Value>,
+) -> Result<Value> {
+    let mut stack = vec![];
+
+    for instr in expr {
+        match instr {
+            Instr::ConstI32(n) => stack.push(Value::I32(*n)),
+            Instr::ConstI64(n) => stack.push(Value::I64(*n)),
+            Instr::ConstF64(n) => stack.push(Value::F64(*n)),
+            Instr::GlobalGet(idx) => stack.push(global_get(*idx)?),
+            Instr::End => break,
+            _ => bail!("{instr:?} is not allowed in a constant expression"),
+        }
+    }
+
+    match (stack.pop(), stack.is_empty()) {
+        (Some(value), true) => Ok(value),
+        _ => bail!("constant expression must produce exactly one value"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, imports::Imports, instance::Instance, test_util::*};
+
+    fn parse(mut bytes: &[u8]) -> Result<Instr> {
+        Module::parse_instruction(&mut bytes, 0)
+    }
+
+    #[test]
+    fn section_bytes() {
+        let bytes = single_func(&[], &[], &[], vec![0x0b]);
+        let module = Module::from_bytes(&Store::default(), &bytes).unwrap();
+        let exports = section(7, vec(vec![export("f", FUNC, 0)]));
+        let start = bytes
+            .windows(exports.len())
+            .position(|w| w == exports)
+            .unwrap();
+        // The id and size aren't part of the contents
+        assert_eq!(
+            module.section_bytes(7).unwrap(),
+            &bytes[start + 2..start + exports.len()]
+        );
+        assert_eq!(module.section_bytes(11), None);
+
+        // Sections that aren't decoded are kept too
+        let table = section(4, vec(vec![vec![0x70, 0x00, 1]]));
+        let bytes = [&bytes[..], &table].concat();
+        let module = Module::from_bytes(&Store::default(), &bytes).unwrap();
+        assert_eq!(module.section_bytes(4).unwrap(), &table[2..]);
+        assert_eq!(module.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn content_hash() {
+        let bytes = single_func(&[], &[I32], &[], vec![0x41, 1, 0x0b]);
+        let a = Module::from_bytes(&Store::default(), &bytes).unwrap();
+        let (b, _) = Module::from_bytes_lenient(&Store::default(), &bytes).unwrap();
+        assert_eq!(a.content_hash(), b.content_hash());
+
+        let other = single_func(&[], &[I32], &[], vec![0x41, 2, 0x0b]);
+        let c = Module::from_bytes(&Store::default(), &other).unwrap();
+        assert_ne!(a.content_hash(), c.content_hash());
+
+        // Stripped sections don't count
+        let custom = section(0, name("notes"));
+        let mut d = Module::from_bytes(&Store::default(), &[&bytes[..], &custom].concat()).unwrap();
+        assert_ne!(a.content_hash(), d.content_hash());
+        d.strip_custom_sections();
+        assert_eq!(a.content_hash(), d.content_hash());
+    }
+
+    #[test]
+    fn truncated_bytes() {
+        // memory.size without its reserved byte, a prefix without its
+        // sub-opcode and a type section ending before the functype tag
+        let e = parse(&[0x3f]).unwrap_err();
+        assert_eq!(e.to_string(), "unexpected end of section");
+        let e = parse(&[0xfc]).unwrap_err();
+        assert_eq!(e.to_string(), "unexpected end of input in LEB128 value");
+        let bytes = [&b"\0asm\x01\0\0\0"[..], &[1, 1, 1]].concat();
+        let e = Module::from_bytes(&Store::default(), &bytes).unwrap_err();
+        assert!(
+            format!("{e:#}").contains("unexpected end of section"),
+            "{e:#}"
+        );
+    }
+
+    #[test]
+    fn prefixed_instructions() {
+        assert_eq!(parse(&[0xfc, 8, 3, 0]).unwrap(), Instr::MemoryInit(3));
+        assert_eq!(parse(&[0xfc, 9, 2]).unwrap(), Instr::DataDrop(2));
+        assert_eq!(parse(&[0xfc, 10, 0, 0]).unwrap(), Instr::MemoryCopy);
+        assert_eq!(parse(&[0xfc, 11, 0]).unwrap(), Instr::MemoryFill);
+        assert_eq!(parse(&[0xfe, 3, 0]).unwrap(), Instr::AtomicFence);
+
+        // The sub-opcode is a LEB128
+        assert_eq!(parse(&[0xfc, 0x89, 0, 2]).unwrap(), Instr::DataDrop(2));
+
+        let e = parse(&[0xfd, 12]).unwrap_err();
+        assert_eq!(e.to_string(), "unknown opcode 0xfd 12 at offset 0");
+        let e = parse(&[0xfc, 0x80, 1]).unwrap_err();
+        assert_eq!(e.to_string(), "unknown opcode 0xfc 128 at offset 0");
+    }
+
+    #[test]
+    fn called_imports() {
+        // Only env.used is called, by function 2
+        let bytes = module(vec![
+            section(1, vec(vec![func_type(&[], &[])])),
+            section(
+                2,
+                vec(vec![
+                    import("env", "unused", vec![FUNC, 0]),
+                    import("env", "used", vec![FUNC, 0]),
+                ]),
+            ),
+            section(3, vec(vec![leb(0), leb(0)])),
+            section(
+                10,
+                vec(vec![
+                    body(&[], vec![0x10, 1, 0x10, 3, 0x0b]),
+                    body(&[], vec![0x0b]),
+                ]),
+            ),
+        ]);
+        let module = Module::from_bytes(&Store::default(), &bytes).unwrap();
+        assert_eq!(module.called_imports(), HashSet::from([1]));
+    }
+
+    #[test]
+    fn name_section_anywhere() {
+        // Function names subsection
+        let subsection = vec(vec![
+            [leb(0), name("add")].concat(),
+            [leb(1), name("sub")].concat(),
+        ]);
+        let contents = [
+            name("name"),
+            vec![1],
+            leb(subsection.len() as u64),
+            subsection,
+        ]
+        .concat();
+        let names = section(0, contents);
+        let funcs = || {
+            vec![
+                ("add", func_type(&[], &[]), vec![0x0b]),
+                ("sub", func_type(&[], &[]), vec![0x0b]),
+            ]
+        };
+
+        // Sorted before the type section
+        let before = funcs_module(funcs(), vec![], vec![names.clone()]);
+        let after = [funcs_module(funcs(), vec![], vec![]), names].concat();
+        for bytes in [before, after] {
+            let module = Module::from_bytes(&Store::default(), &bytes).unwrap();
+            let names: Vec<_> = module.funcs.iter().map(Func::name).collect();
+            assert_eq!(names, [Some("add"), Some("sub")]);
+        }
+    }
+
+    #[test]
+    fn lazy_function_bodies() {
+        // The body of "bad" ends in the middle of a local index
+        let bytes = funcs_module(
+            vec![
+                (
+                    "add",
+                    func_type(&[I32, I32], &[I32]),
+                    vec![0x20, 0, 0x20, 1, 0x6a, 0x0b],
+                ),
+                ("bad", func_type(&[I32, I32], &[I32]), vec![0x20, 0x80]),
+            ],
+            vec![],
+            vec![],
+        );
+        assert!(Module::from_bytes(&Store::default(), &bytes).is_err());
+
+        let mut store = Store::new(Config {
+            lazy_function_bodies: true,
+            ..Default::default()
+        });
+        let module = Module::from_bytes(&store, &bytes).unwrap();
+        assert!(!module.funcs[0].is_decoded() && !module.funcs[1].is_decoded());
+        let instance = Instance::new(&mut store, module, &Imports::new()).unwrap();
+        let add = instance.exports.get_function("add").unwrap();
+        let args = [Value::I32(1), Value::I32(2)];
+        assert_eq!(add.call(&mut store, &args).unwrap(), [Value::I32(3)]);
+        let e = instance.exports.get_function("bad").err().unwrap();
+        assert!(
+            format!("{e:#}").contains("parse body of function 1"),
+            "{e:#}"
+        );
+    }
+
+    #[test]
+    fn absurd_param_count() {
+        let ty = [vec![0x60], leb(u32::MAX as u64)].concat();
+        let bytes = module(vec![section(1, vec(vec![ty]))]);
+        let e = Module::from_bytes(&Store::default(), &bytes).unwrap_err();
+        assert!(
+            format!("{e:#}").contains("too many params (4294967295)"),
+            "{e:#}"
+        );
+    }
+
+    #[test]
+    fn accepts() {
+        let values = [
+            Value::I32(0),
+            Value::I64(0),
+            Value::F32(0.0),
+            Value::F64(0.0),
+        ];
+        for (ty, value) in [Val::I32, Val::I64, Val::F32, Val::F64].iter().zip(&values) {
+            for other in &values {
+                assert_eq!(ty.accepts(other), other == value, "{ty:?} {other:?}");
+            }
+        }
+        // There are no reference values
+        for ty in [Val::V128, Val::FuncRef, Val::ExternRef] {
+            assert!(values.iter().all(|value| !ty.accepts(value)), "{ty:?}");
+        }
+    }
+
+    #[test]
+    fn func_type_builder() {
+        let built = FuncType::default()
+            .param(Val::I32)
+            .param(Val::I64)
+            .result(Val::F32);
+        assert_eq!(
+            FuncType::new(vec![Val::I32, Val::I64], vec![Val::F32]),
+            built
+        );
+        assert_ne!(
+            FuncType::default().param(Val::I32),
+            FuncType::default().result(Val::I32)
+        );
+    }
+
+    #[test]
+    fn exported_functions() {
+        let module =
+            Module::from_bytes(&Store::default(), include_bytes!("../example.wasm")).unwrap();
+        let add = FuncType::new(vec![Val::I32, Val::I32], vec![Val::I32]);
+        assert_eq!(module.exported_functions(), [("add".to_string(), add)]);
+    }
+
+    #[test]
+    fn max_functions() {
+        let bytes = single_func(&[], &[], &[], vec![0x0b]);
+        let store = Store::new(Config {
+            max_functions: Some(0),
+            ..Default::default()
+        });
+        let e = Module::from_bytes(&store, &bytes).unwrap_err();
+        assert_eq!(
+            format!("{e:#}"),
+            "parse function section: module declares 1 functions, the maximum is 0"
+        );
+    }
+
+    #[test]
+    fn version() {
+        let example =
+            Module::from_bytes(&Store::default(), include_bytes!("../example2.wasm")).unwrap();
+        assert_eq!(example.version(), Version::CORE);
+
+        let mut bytes = module(vec![]);
+        bytes[4] = 0x0d;
+        bytes[6] = 1;
+        let e = Module::from_bytes(&Store::default(), &bytes).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "unsupported version [0d, 00, 01, 00] (version 13, layer 1), only core modules (version 1, layer 0) are supported"
+        );
+    }
+
+    #[test]
+    fn from_bytes_lenient() {
+        // The export section claims 5 exports but holds part of one
+        let bytes = module(vec![
+            section(1, vec(vec![func_type(&[I32, I32], &[I32])])),
+            section(3, vec(vec![leb(0)])),
+            section(7, vec![5, 3, b'a']),
+            section(10, vec(vec![body(&[], vec![0x20, 0, 0x20, 1, 0x6a, 0x0b])])),
+        ]);
+        assert!(Module::from_bytes(&Store::default(), &bytes).is_err());
+
+        let (module, errors) = Module::from_bytes_lenient(&Store::default(), &bytes).unwrap();
+        assert_eq!(module.funcs.len(), 1);
+        assert!(module.funcs[0].body().is_ok());
+        assert!(module.exports.is_empty());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].id, 7);
+    }
+
+    #[test]
+    fn interleaved_custom_sections() {
+        let custom = || section(0, name("notes"));
+        let bytes = module(vec![
+            section(1, vec(vec![func_type(&[I32, I32], &[I32])])),
+            custom(),
+            section(3, vec(vec![leb(0)])),
+            custom(),
+            section(10, vec(vec![body(&[], vec![0x20, 0, 0x20, 1, 0x6a, 0x0b])])),
+            custom(),
+            section(7, vec(vec![export("add", FUNC, 0)])),
+        ]);
+        let mut store = Store::default();
+        let add = instantiate(&mut store, &bytes)
+            .exports
+            .get_function("add")
+            .unwrap();
+        let args = [Value::I32(1), Value::I32(2)];
+        assert_eq!(add.call(&mut store, &args).unwrap(), [Value::I32(3)]);
+    }
+
+    #[test]
+    fn multi_byte_immediate_at_body_end() {
+        // "a" declares locals and ends with a three byte i32.const immediate
+        let bytes = module(vec![
+            section(1, vec(vec![func_type(&[], &[I32])])),
+            section(3, vec(vec![leb(0), leb(0)])),
+            section(7, vec(vec![export("a", FUNC, 0), export("b", FUNC, 1)])),
+            section(
+                10,
+                vec(vec![
+                    body(&[(2, I32), (130, I64)], vec![0x41, 0xc0, 0xbb, 0x78, 0x0b]),
+                    body(&[], vec![0x41, 5, 0x0b]),
+                ]),
+            ),
+        ]);
+        let mut store = Store::default();
+        let exports = instantiate(&mut store, &bytes).exports;
+        let a = exports.get_function("a").unwrap();
+        assert_eq!(a.call(&mut store, &[]).unwrap(), [Value::I32(-123456)]);
+        let b = exports.get_function("b").unwrap();
+        assert_eq!(b.call(&mut store, &[]).unwrap(), [Value::I32(5)]);
+    }
+
+    #[test]
+    fn max_exports() {
+        let bytes = funcs_module(
+            vec![("a", func_type(&[], &[]), vec![0x0b])],
+            vec![export("b", FUNC, 0), export("c", FUNC, 0)],
+            vec![],
+        );
+        let config = |max| Config {
+            max_exports: Some(max),
+            ..Default::default()
+        };
+        let e = Module::from_bytes(&Store::new(config(2)), &bytes).unwrap_err();
+        assert!(
+            format!("{e:#}").contains("3 exports, the maximum is 2"),
+            "{e:#}"
+        );
+        assert!(Module::from_bytes(&Store::new(config(3)), &bytes).is_ok());
+    }
+
+    #[test]
+    fn eval_const_expr() {
+        // Global 1 is initialized from global 0, global 2 from itself
+        let globals = vec![
+            vec![I32, 0, 0x41, 7, 0x0b],
+            vec![I32, 0, 0x23, 0, 0x0b],
+            vec![I32, 0, 0x23, 2, 0x0b],
+        ];
+        let bytes = module(vec![section(6, vec(globals))]);
+        let module = Module::from_bytes(&Store::default(), &bytes).unwrap();
+
+        let eval = |expr: &[Instr]| module.eval_const_expr(expr);
+        assert_eq!(
+            eval(&[Instr::ConstI32(5), Instr::End]).unwrap(),
+            Value::I32(5)
+        );
+        assert_eq!(
+            eval(&[Instr::GlobalGet(1), Instr::End]).unwrap(),
+            Value::I32(7)
+        );
+        assert!(eval(&[Instr::GlobalGet(2), Instr::End]).is_err());
+        assert!(eval(&[Instr::GlobalGet(9), Instr::End]).is_err());
+        assert!(eval(&[Instr::I32Add, Instr::End]).is_err());
+        assert!(eval(&[Instr::End]).is_err());
+    }
+
+    #[test]
+    fn gc_types() {
+        // A rec group, a final sub type and a struct type
+        for ty in [
+            vec![0x4e, 1, 0x60, 0, 0],
+            vec![0x50, 0, 0x60, 0, 0],
+            vec![0x5f, 0],
+        ] {
+            let bytes = module(vec![section(1, vec(vec![ty]))]);
+            let e = Module::from_bytes(&Store::default(), &bytes).unwrap_err();
+            assert!(format!("{e:#}").contains("unsupported: GC types"), "{e:#}");
+        }
+
+        let bytes = module(vec![section(1, vec(vec![vec![0x61]]))]);
+        let e = Module::from_bytes(&Store::default(), &bytes).unwrap_err();
+        assert!(
+            format!("{e:#}").contains("expected start of functype"),
+            "{e:#}"
+        );
+    }
+
+    #[test]
+    fn i32_const_and_load() {
+        assert_eq!(parse(&[0x41, 7]).unwrap(), Instr::ConstI32(7));
+        let load = Instr::I32Load(MemArg {
+            align: 2,
+            offset: 128,
+        });
+        assert_eq!(parse(&[0x28, 2, 0x80, 0x01]).unwrap(), load);
+
+        let bytes = single_func(&[], &[I32], &[], vec![0x41, 7, 0x0b]);
+        let mut store = Store::default();
+        let module = Module::from_bytes(&store, &bytes).unwrap();
+        assert_eq!(module.to_bytes(), bytes);
+        let f = func(&mut store, &bytes);
+        assert_eq!(f.call(&mut store, &[]).unwrap(), [Value::I32(7)]);
+    }
+
+    #[test]
+    fn strip_custom_sections() {
+        let with_names = |names: bool| {
+            let mut sections = vec![
+                section(1, vec(vec![func_type(&[], &[I32])])),
+                section(3, vec(vec![leb(0)])),
+            ];
+            if names {
+                sections.push(section(0, [name("name"), vec![1, 2, 3]].concat()));
+            }
+            sections.push(section(0, [name("producers"), vec![9]].concat()));
+            sections.push(section(7, vec(vec![export("c", FUNC, 0)])));
+            sections.push(section(10, vec(vec![body(&[], vec![0x41, 7, 0x0b])])));
+            module(sections)
+        };
+        let bytes = with_names(true);
+        let store = Store::default();
+        let mut stripped = Module::from_bytes(&store, &bytes).unwrap();
+        assert_eq!(stripped.to_bytes(), bytes);
+
+        stripped.strip_custom_section("name");
+        let out = stripped.to_bytes();
+        assert!(out.len() < bytes.len());
+        assert_eq!(out, with_names(false));
+        let reparsed = Module::from_bytes(&store, &out).unwrap();
+        let producers = [name("producers"), vec![9]].concat();
+        assert_eq!(reparsed.section_bytes(0), Some(&producers[..]));
+        assert_eq!(reparsed.exported_functions().len(), 1);
+
+        stripped.strip_custom_sections();
+        let reparsed = Module::from_bytes(&store, &stripped.to_bytes()).unwrap();
+        assert!(reparsed.section_bytes(0).is_none());
+    }
+
+    #[test]
+    fn over_long_constants() {
+        // -1 encoded with the maximum number of bytes
+        let i32_minus_one = [0x41, 0xff, 0xff, 0xff, 0xff, 0x7f];
+        assert_eq!(parse(&i32_minus_one).unwrap(), Instr::ConstI32(-1));
+        let mut i64_minus_one = vec![0x42];
+        i64_minus_one.extend([0xff; 9]);
+        i64_minus_one.push(0x7f);
+        assert_eq!(parse(&i64_minus_one).unwrap(), Instr::ConstI64(-1));
+    }
+
+    #[test]
+    fn import_section() {
+        let bytes = module(vec![
+            section(
+                1,
+                vec(vec![func_type(&[I32], &[I32]), func_type(&[], &[I32])]),
+            ),
+            section(
+                2,
+                vec(vec![
+                    import("env", "log", vec![FUNC, 0]),
+                    import("env", "mem", vec![MEMORY, 0x01, 1, 2]),
+                    import("env", "g", vec![GLOBAL, I32, 0x00]),
+                    import("env", "t", vec![0x01, 0x70, 0x00, 3]),
+                ]),
+            ),
+            section(3, vec(vec![leb(1)])),
+            section(7, vec(vec![export("f", FUNC, 1)])),
+            section(10, vec(vec![body(&[], vec![0x41, 7, 0x10, 0, 0x0b])])),
+        ]);
+        let parsed = Module::from_bytes(&Store::default(), &bytes).unwrap();
+        assert_eq!(parsed.imports.len(), 4);
+        assert_eq!(
+            (&*parsed.imports[0].module, &*parsed.imports[0].name),
+            ("env", "log")
+        );
+        assert_eq!(parsed.imports[0].kind, ImportKind::Func(0));
+        let memory = MemoryType {
+            limits: Limits {
+                min: 1,
+                max: Some(2),
+            },
+        };
+        assert_eq!(parsed.imports[1].kind, ImportKind::Memory(memory));
+        assert!(matches!(parsed.imports[3].kind, ImportKind::Table(_)));
+
+        // The defined function comes after the imported one
+        assert_eq!(parsed.num_imported_funcs(), 1);
+        assert_eq!(parsed.exports[0].idx, 1);
+        let ty = FuncType::new(vec![], vec![Val::I32]);
+        assert_eq!(parsed.exported_functions(), [("f".to_string(), ty)]);
+        assert_eq!(parsed.func_type(0).unwrap().params, [Val::I32]);
+        assert!(parsed.func_type(2).is_none());
+
+        let truncated = module(vec![
+            section(1, vec(vec![func_type(&[], &[])])),
+            section(2, vec(vec![[name("env"), name("x")].concat()])),
+        ]);
+        assert!(Module::from_bytes(&Store::default(), &truncated).is_err());
+    }
+
+    #[test]
+    fn func_type_compatibility() {
+        let ty = FuncType::new(vec![Val::I32, Val::I64], vec![Val::I32]);
+        assert!(ty.is_compatible_with(&ty.clone()));
+        // Different param counts, then result types and counts
+        assert!(!ty.is_compatible_with(&FuncType::new(vec![Val::I32], vec![Val::I32])));
+        assert!(!ty.is_compatible_with(&FuncType::new(vec![Val::I32, Val::I64], vec![Val::I64])));
+        assert!(!ty.is_compatible_with(&FuncType::new(vec![Val::I32, Val::I64], vec![])));
+    }
+
+    #[test]
+    fn add_type() {
+        let ty = FuncType::new(vec![Val::I32], vec![Val::I32]);
+        let bytes = single_func(&[I32], &[I32], &[], vec![0x20, 0, 0x0b]);
+        let store = Store::default();
+        let mut types = Module::from_bytes(&store, &bytes).unwrap();
+        // Identical types are deduplicated, and the module left unchanged
+        assert_eq!(types.add_type(ty), 0);
+        assert_eq!(types.to_bytes(), bytes);
+
+        let other = FuncType::new(vec![Val::F64, Val::I64], vec![]);
+        assert_eq!(types.add_type(other.clone()), 1);
+        assert_eq!(types.add_type(other.clone()), 1);
+        assert_eq!(types.types().len(), 2);
+        let reparsed = Module::from_bytes(&store, &types.to_bytes()).unwrap();
+        assert_eq!(reparsed.types(), types.types());
+        assert_eq!(reparsed.funcs.len(), 1);
+
+        // A type section is added to modules without one
+        let mut empty = Module::default();
+        assert_eq!(empty.add_type(other.clone()), 0);
+        let reparsed = Module::from_bytes(&store, &empty.to_bytes()).unwrap();
+        assert_eq!(reparsed.types(), [other]);
+    }
+
+    #[test]
+    fn producers() {
+        let field = |field: &str, name_: &str, version: &str| {
+            [
+                name(field),
+                vec(vec![[name(name_), name(version)].concat()]),
+            ]
+            .concat()
+        };
+        let contents = [
+            name("producers"),
+            vec(vec![
+                field("language", "Rust", ""),
+                field("processed-by", "rustc", "1.95.0"),
+            ]),
+        ]
+        .concat();
+        let bytes = module(vec![section(0, contents)]);
+        let producers = Module::from_bytes(&Store::default(), &bytes)
+            .unwrap()
+            .producers()
+            .unwrap();
+        assert_eq!(producers.language[0].name, "Rust");
+        assert_eq!(producers.processed_by[0].name, "rustc");
+        assert_eq!(producers.processed_by[0].version, "1.95.0");
+        assert!(producers.sdk.is_empty());
+
+        let bytes = module(vec![]);
+        let parsed = Module::from_bytes(&Store::default(), &bytes).unwrap();
+        assert_eq!(parsed.producers(), None);
+    }
+
+    #[test]
+    fn rename_export() {
+        let bytes = funcs_module(
+            vec![(
+                "add",
+                func_type(&[I32, I32], &[I32]),
+                vec![0x20, 0, 0x20, 1, 0x6a, 0x0b],
+            )],
+            vec![export("other", FUNC, 0)],
+            vec![],
+        );
+        let mut store = Store::default();
+        let mut parsed = Module::from_bytes(&store, &bytes).unwrap();
+        assert!(parsed.rename_export("add", "other").is_err());
+        assert!(parsed.rename_export("missing", "x").is_err());
+        parsed.rename_export("add", "sum").unwrap();
+
+        let exports = instantiate(&mut store, &parsed.to_bytes()).exports;
+        assert!(exports.get_function("add").is_err());
+        let sum = exports.get_function("sum").unwrap();
+        assert_eq!(
+            sum.call(&mut store, &[Value::I32(2), Value::I32(3)])
+                .unwrap(),
+            [Value::I32(5)]
+        );
+    }
+
+    #[test]
+    fn merge_code_section() {
+        let bytes = funcs_module(
+            vec![(
+                "add",
+                func_type(&[I32, I32], &[I32]),
+                vec![0x20, 0, 0x20, 1, 0x6a, 0x0b],
+            )],
+            vec![],
+            vec![],
+        );
+        let mut store = Store::default();
+        let mut parsed = Module::from_bytes(&store, &bytes).unwrap();
+
+        // One body too many
+        let sub = body(&[], vec![0x20, 0, 0x20, 1, 0x6b, 0x0b]);
+        assert!(parsed
+            .merge_code_section(&vec(vec![sub.clone(), sub.clone()]))
+            .is_err());
+
+        parsed.merge_code_section(&vec(vec![sub])).unwrap();
+        let add = Instance::new(&mut store, parsed, &Imports::new())
+            .unwrap()
+            .exports
+            .get_function("add")
+            .unwrap();
+        assert_eq!(
+            add.call(&mut store, &[Value::I32(5), Value::I32(3)])
+                .unwrap(),
+            [Value::I32(2)]
+        );
+    }
+
+    #[test]
+    fn reachable_functions() {
+        let bytes = funcs_module(
+            vec![
+                ("main", func_type(&[], &[]), vec![0x10, 1, 0x0b]),
+                // Calls itself, which mustn't loop forever
+                ("helper", func_type(&[], &[]), vec![0x10, 1, 0x0b]),
+                ("unused", func_type(&[], &[]), vec![0x0b]),
+            ],
+            vec![],
+            vec![],
+        );
+        let parsed = Module::from_bytes(&Store::default(), &bytes).unwrap();
+        assert_eq!(parsed.reachable_functions(&[0]), HashSet::from([0, 1]));
+        assert_eq!(parsed.reachable_functions(&[2]), HashSet::from([2]));
+        assert_eq!(parsed.reachable_functions(&[]), HashSet::new());
+    }
+
+    #[test]
+    fn from_bytes() {
+        let store = Store::default();
+        let bytes = include_bytes!("../example2.wasm");
+        let parsed = Module::from_bytes(&store, bytes).unwrap();
+        let from_file = Module::from_file(&store, "example2.wasm").unwrap();
+        assert_eq!(parsed.exports, from_file.exports);
+        assert_eq!(parsed.funcs.len(), from_file.funcs.len());
+        assert_eq!(parsed.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        let bytes = single_func(&[], &[I32], &[], vec![0x41, 1, 0xd7, 0x0b]);
+        let e = Module::from_bytes(&Store::default(), &bytes).unwrap_err();
+        assert!(
+            format!("{e:#}").contains("unknown opcode 0xd7 at offset 2"),
+            "{e:#}"
+        );
+    }
+
+    #[test]
+    fn too_many_locals() {
+        let with_locals = |locals: &[(u32, u8)]| single_func(&[], &[], locals, vec![0x0b]);
+        let store = Store::default();
+        let parsed = Module::from_bytes(&store, &with_locals(&[(MAX_LOCALS, I32)])).unwrap();
+        assert_eq!(parsed.funcs[0].locals().len(), MAX_LOCALS as usize);
+
+        for locals in [
+            &[(0xFFFFFFF0, I32)][..],
+            &[(30_000, I32), (30_000, I64)],
+            &[(0xFFFFFFFF, I32), (1, I32)],
+        ] {
+            let e = Module::from_bytes(&store, &with_locals(locals)).unwrap_err();
+            assert_eq!(
+                format!("{e:#}"),
+                "parse code section: function 0 declares too many locals, the maximum is 50000"
+            );
+        }
+    }
+
+    #[test]
+    fn missing_code_section() {
+        let bytes = module(vec![
+            section(1, vec(vec![func_type(&[], &[])])),
+            section(3, vec(vec![leb(0)])),
+            section(7, vec(vec![export("f", FUNC, 0)])),
+        ]);
+        for lazy_function_bodies in [false, true] {
+            let store = Store::new(Config {
+                lazy_function_bodies,
+                ..Default::default()
+            });
+            let e = Module::from_bytes(&store, &bytes).unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "function and code section have inconsistent lengths"
+            );
+        }
+
+        let (parsed, errors) = Module::from_bytes_lenient(&Store::default(), &bytes).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].id, 0x0A);
+        assert!(parsed.funcs[0].body().is_err());
+
+        // Without functions the code section is optional
+        let bytes = module(vec![section(1, vec(vec![func_type(&[], &[])]))]);
+        Module::from_bytes(&Store::default(), &bytes).unwrap();
+    }
+
+    #[test]
+    fn const_expr_globals() {
+        // Globals 0 and 1 are imported, 0 immutable and 1 mutable, then
+        // global 2 is defined immutable and global 3 mutable
+        let bytes = module(vec![
+            section(
+                2,
+                vec(vec![
+                    import("env", "a", vec![GLOBAL, I32, 0]),
+                    import("env", "b", vec![GLOBAL, I32, 1]),
+                ]),
+            ),
+            section(
+                6,
+                vec(vec![
+                    vec![I32, 0, 0x41, 7, 0x0b],
+                    vec![I32, 1, 0x41, 8, 0x0b],
+                ]),
+            ),
+        ]);
+        let parsed = Module::from_bytes(&Store::default(), &bytes).unwrap();
+        let get = |idx| [Instr::GlobalGet(idx), Instr::End];
+        let imported = [Value::I32(5), Value::I32(6)];
+        let eval = |idx| parsed.eval_const_expr_with_imports(&get(idx), &imported);
+
+        assert_eq!(eval(0).unwrap(), Value::I32(5));
+        assert_eq!(eval(2).unwrap(), Value::I32(7));
+        for idx in [1, 3] {
+            assert_eq!(
+                eval(idx).unwrap_err().to_string(),
+                format!("global {idx} is mutable, it can't be used in a constant expression")
+            );
+        }
+
+        let e = parsed.eval_const_expr(&get(0)).unwrap_err();
+        assert_eq!(e.to_string(), "global 0 is imported, its value isn't known");
+        let e = parsed
+            .eval_const_expr_with_imports(&get(0), &[Value::I64(5)])
+            .unwrap_err();
+        assert_eq!(e.to_string(), "global 0 has type I32, got I64(5)");
+    }
+
+    #[test]
+    fn malformed_name_section() {
+        // Names function 0, then the local names subsection ends early
+        let funcs = vec(vec![[leb(0), name("add")].concat()]);
+        let locals = [leb(1), leb(0), leb(1), leb(0)].concat();
+        let contents = [
+            name("name"),
+            vec![1],
+            leb(funcs.len() as u64),
+            funcs,
+            vec![2],
+            leb(locals.len() as u64),
+            locals,
+        ]
+        .concat();
+        let bytes = funcs_module(
+            vec![("add", func_type(&[I32], &[]), vec![0x0b])],
+            vec![],
+            vec![section(0, contents)],
+        );
+        let parsed = Module::from_bytes(&Store::default(), &bytes).unwrap();
+        assert_eq!(parsed.funcs[0].name(), None);
+        assert_eq!(parsed.funcs[0].local_name(0), None);
+    }
 }
@@ -0,0 +1,139 @@
+//! Per-function instruction counts, collected when profiling is enabled on a
+//! [`Store`](crate::store::Store).
+
+use std::collections::BTreeMap;
+
+/// Instruction counts of everything that ran since profiling was enabled,
+/// keyed by function index.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    pub functions: BTreeMap<u32, FunctionProfile>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FunctionProfile {
+    /// Number of times the function was entered.
+    pub calls: u64,
+    /// Instructions executed by the function itself.
+    pub self_instructions: u64,
+    /// Instructions executed by the function and everything it called.
+    /// Recursive calls are only counted once.
+    pub total_instructions: u64,
+    /// Instructions executed by each callee, including its own callees, when
+    /// called from this function.
+    pub children: BTreeMap<u32, u64>,
+}
+
+struct Frame {
+    idx: u32,
+    instructions: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct Profiler {
+    profile: Profile,
+    frames: Vec<Frame>,
+}
+
+impl Profiler {
+    pub(crate) fn enter(&mut self, idx: u32) {
+        self.profile.functions.entry(idx).or_default().calls += 1;
+        self.frames.push(Frame {
+            idx,
+            instructions: 0,
+        });
+    }
+
+    pub(crate) fn count(&mut self) {
+        // Profiling may have been enabled in the middle of a call
+        let Some(frame) = self.frames.last_mut() else {
+            return;
+        };
+        frame.instructions += 1;
+        self.profile
+            .functions
+            .entry(frame.idx)
+            .or_default()
+            .self_instructions += 1;
+    }
+
+    pub(crate) fn exit(&mut self) {
+        let Some(frame) = self.frames.pop() else {
+            return;
+        };
+
+        // A recursive call is already part of its outer call's total
+        if !self.frames.iter().any(|f| f.idx == frame.idx) {
+            self.profile
+                .functions
+                .entry(frame.idx)
+                .or_default()
+                .total_instructions += frame.instructions;
+        }
+
+        if let Some(parent) = self.frames.last_mut() {
+            parent.instructions += frame.instructions;
+            *self
+                .profile
+                .functions
+                .entry(parent.idx)
+                .or_default()
+                .children
+                .entry(frame.idx)
+                .or_default() += frame.instructions;
+        }
+    }
+
+    pub(crate) fn into_profile(self) -> Profile {
+        self.profile
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{store::Store, test_util::*};
+
+    #[test]
+    fn helper_called_in_a_loop() {
+        // "f" calls function 1 three times from a loop
+        #[rustfmt::skip]
+        let f = vec![
+            0x41, 3, 0x21, 0,
+            0x03, 0x40,
+                0x10, 1,
+                0x20, 0, 0x41, 1, 0x6b, 0x22, 0, 0x0d, 0,
+            0x0b,
+            0x0b,
+        ];
+        let bytes = module(vec![
+            section(1, vec(vec![func_type(&[], &[])])),
+            section(3, vec(vec![leb(0), leb(0)])),
+            section(7, vec(vec![export("f", FUNC, 0)])),
+            section(
+                10,
+                vec(vec![body(&[(1, I32)], f), body(&[], vec![0x01, 0x0b])]),
+            ),
+        ]);
+        let mut store = Store::default();
+        let f = func(&mut store, &bytes);
+        store.enable_profiling();
+        f.call(&mut store, &[]).unwrap();
+        let profile = store.take_profile().unwrap();
+
+        // nop and end
+        let helper = &profile.functions[&1];
+        assert_eq!(helper.calls, 3);
+        assert_eq!(helper.self_instructions, 6);
+        assert_eq!(helper.total_instructions, 6);
+        assert!(helper.children.is_empty());
+
+        // Two instructions before the loop, the loop, 6 per iteration with
+        // the call and the two ends
+        let f = &profile.functions[&0];
+        assert_eq!(f.calls, 1);
+        assert_eq!(f.self_instructions, 2 + 1 + 3 * 6 + 2);
+        assert_eq!(f.total_instructions, f.self_instructions + 6);
+        assert_eq!(f.children, BTreeMap::from([(1, 6)]));
+    }
+}
@@ -1,2 +1,161 @@
+use std::{collections::HashMap, io::Write, time::Instant};
+
+use anyhow::Result;
+
+use crate::{
+    config::{Config, DEFAULT_DEADLINE_CHECK_INTERVAL},
+    error::TrapKind,
+    instance::Value,
+    module::Instr,
+    profile::{Profile, Profiler},
+};
+
+/// Called with the instruction about to run and the operand stack. Returns
+/// `true` when it handled the instruction, which then isn't executed.
+pub type Interceptor = Box<dyn FnMut(&Instr, &mut Vec<Value>, &mut Store) -> Result<bool>>;
+
 #[derive(Default)]
-pub struct Store {}
+pub struct Store {
+    pub(crate) config: Config,
+    pub(crate) trace: Option<Box<dyn Write>>,
+    // Values of the globals of every instance in this store, instances refer
+    // to them by address so their state outlives a single call.
+    pub(crate) globals: Vec<Value>,
+    // Same for the linear memories.
+    pub(crate) memories: Vec<Vec<u8>>,
+    // And for the bytes of the data segments, emptied once they are dropped.
+    pub(crate) datas: Vec<Vec<u8>>,
+    // Keyed by opcode, see `Instr::opcode`, which is the prefix byte of
+    // prefixed instructions.
+    pub(crate) interceptors: HashMap<u8, Interceptor>,
+    pub(crate) profiler: Option<Profiler>,
+    // Number of wasm functions currently running.
+    pub(crate) call_depth: usize,
+    pub(crate) coredumps: bool,
+    pub(crate) deadline: Option<Instant>,
+    // Instructions left to run before the deadline is checked again.
+    pub(crate) deadline_countdown: u32,
+}
+
+impl Store {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Records every executed instruction to `out`, one
+    /// `<function index> <instruction offset> <opcode>` line per instruction.
+    /// The output is deterministic and can be diffed across runs.
+    pub fn set_trace_output(&mut self, out: impl Write + 'static) {
+        self.trace = Some(Box::new(out));
+    }
+
+    /// Registers `interceptor` to run before every instruction with the given
+    /// opcode, replacing any previous interceptor for it. Only the first byte
+    /// of the opcode is matched, so the instructions with a prefix byte, like
+    /// the 0xFC bulk memory ones, all share the interceptor of their prefix.
+    pub fn intercept(
+        &mut self,
+        opcode: u8,
+        interceptor: impl FnMut(&Instr, &mut Vec<Value>, &mut Store) -> Result<bool> + 'static,
+    ) {
+        self.interceptors.insert(opcode, Box::new(interceptor));
+    }
+
+    /// Traps running code with a timeout once `deadline` has passed. The clock
+    /// is only read every `Config::deadline_check_interval` instructions, so
+    /// the trap can come slightly late.
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+        self.deadline_countdown = 0;
+    }
+
+    /// Removes the deadline set with [`Store::set_deadline`], so that code can
+    /// run again after timing out.
+    pub fn clear_deadline(&mut self) {
+        self.deadline = None;
+    }
+
+    // Called before every instruction while a deadline is set.
+    pub(crate) fn check_deadline(&mut self, deadline: Instant) -> Result<()> {
+        if self.deadline_countdown > 0 {
+            self.deadline_countdown -= 1;
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            return Err(TrapKind::Timeout.with_detail("the deadline has passed"));
+        }
+        self.deadline_countdown = self
+            .config
+            .deadline_check_interval
+            .unwrap_or(DEFAULT_DEADLINE_CHECK_INTERVAL);
+        Ok(())
+    }
+
+    /// Attaches a [`CoreDump`](crate::coredump::CoreDump) of the call stack to
+    /// [`Trap`](crate::error::Trap)s.
+    pub fn enable_coredumps(&mut self) {
+        self.coredumps = true;
+    }
+
+    /// Starts counting executed instructions per function, discarding any
+    /// profile collected so far.
+    pub fn enable_profiling(&mut self) {
+        self.profiler = Some(Profiler::default());
+    }
+
+    /// Stops profiling and returns what was collected, `None` if profiling
+    /// wasn't enabled.
+    pub fn take_profile(&mut self) -> Option<Profile> {
+        self.profiler.take().map(Profiler::into_profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn intercept() {
+        let mut store = Store::default();
+        let exports = instantiate(&mut store, include_bytes!("../example.wasm")).exports;
+        let add = exports.get_function("add").unwrap();
+        let args = [Value::I32(1), Value::I32(2)];
+
+        store.intercept(0x6a, |_, stack, _| {
+            stack.truncate(stack.len() - 2);
+            stack.push(Value::I32(0));
+            Ok(true)
+        });
+        assert_eq!(add.call(&mut store, &args).unwrap(), [Value::I32(0)]);
+
+        // Not handling the instruction runs it as usual
+        store.intercept(0x6a, |_, _, _| Ok(false));
+        assert_eq!(add.call(&mut store, &args).unwrap(), [Value::I32(3)]);
+    }
+
+    #[test]
+    fn intercepted_branch_below_its_block() {
+        // 5 + (block (result i32) 1 br 0)
+        let code = vec![0x41, 5, 0x02, I32, 0x41, 1, 0x0c, 0, 0x0b, 0x6a, 0x0b];
+        let bytes = single_func(&[], &[I32], &[], code);
+        let mut store = Store::default();
+        let f = func(&mut store, &bytes);
+        // Leaves only the branch value, popping the 5 under the block
+        store.intercept(0x0c, |_, stack, _| {
+            stack.clear();
+            stack.push(Value::I32(9));
+            Ok(false)
+        });
+        let e = f.call(&mut store, &[]).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("branch needs 1 values above the 1 of the enclosing blocks but the stack only has 1"),
+            "{e}"
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! Helpers to build small modules byte by byte in tests.
+
+#![allow(dead_code)]
+
+use crate::{
+    imports::Imports,
+    instance::{Function, Instance},
+    module::Module,
+    store::Store,
+};
+
+pub const I32: u8 = 0x7f;
+pub const I64: u8 = 0x7e;
+pub const F32: u8 = 0x7d;
+pub const F64: u8 = 0x7c;
+
+pub const FUNC: u8 = 0x00;
+pub const MEMORY: u8 = 0x02;
+pub const GLOBAL: u8 = 0x03;
+
+pub fn leb(mut value: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return out;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub fn sleb(mut value: i64) -> Vec<u8> {
+    let mut out = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return out;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub fn section(id: u8, contents: Vec<u8>) -> Vec<u8> {
+    [vec![id], leb(contents.len() as u64), contents].concat()
+}
+
+// A vector of already encoded items, prefixed by their count.
+pub fn vec(items: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut out = leb(items.len() as u64);
+    items.into_iter().for_each(|item| out.extend(item));
+    out
+}
+
+pub fn name(name: &str) -> Vec<u8> {
+    [leb(name.len() as u64), name.as_bytes().to_vec()].concat()
+}
+
+pub fn func_type(params: &[u8], results: &[u8]) -> Vec<u8> {
+    [
+        vec![0x60],
+        leb(params.len() as u64),
+        params.to_vec(),
+        leb(results.len() as u64),
+        results.to_vec(),
+    ]
+    .concat()
+}
+
+// An entry of the code section, `code` including the final `end`.
+pub fn body(locals: &[(u32, u8)], code: Vec<u8>) -> Vec<u8> {
+    let mut contents = leb(locals.len() as u64);
+    for (count, ty) in locals {
+        contents.extend(leb(*count as u64));
+        contents.push(*ty);
+    }
+    contents.extend(code);
+    [leb(contents.len() as u64), contents].concat()
+}
+
+pub fn export(name_: &str, kind: u8, idx: u32) -> Vec<u8> {
+    [name(name_), vec![kind], leb(idx as u64)].concat()
+}
+
+pub fn import(module: &str, name_: &str, desc: Vec<u8>) -> Vec<u8> {
+    [name(module), name(name_), desc].concat()
+}
+
+// A data segment copied into memory 0 at `offset` during instantiation.
+pub fn active_data(offset: i32, bytes: &[u8]) -> Vec<u8> {
+    [
+        vec![0x00, 0x41],
+        sleb(offset as i64),
+        vec![0x0b],
+        leb(bytes.len() as u64),
+        bytes.to_vec(),
+    ]
+    .concat()
+}
+
+// A data segment only copied by `memory.init`.
+pub fn passive_data(bytes: &[u8]) -> Vec<u8> {
+    [vec![0x01], leb(bytes.len() as u64), bytes.to_vec()].concat()
+}
+
+pub fn module(sections: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut out = b"\0asm\x01\0\0\0".to_vec();
+    sections.into_iter().for_each(|section| out.extend(section));
+    out
+}
+
+// A module with one function of type `params` to `results`, exported as "f".
+pub fn single_func(params: &[u8], results: &[u8], locals: &[(u32, u8)], code: Vec<u8>) -> Vec<u8> {
+    module(vec![
+        section(1, vec(vec![func_type(params, results)])),
+        section(3, vec(vec![leb(0)])),
+        section(7, vec(vec![export("f", FUNC, 0)])),
+        section(10, vec(vec![body(locals, code)])),
+    ])
+}
+
+// A module with one function per entry of `funcs`, exported under its name
+// along with `exports`, and `extra` sections placed where their ids say.
+pub fn funcs_module(
+    funcs: Vec<(&str, Vec<u8>, Vec<u8>)>,
+    exports: Vec<Vec<u8>>,
+    extra: Vec<Vec<u8>>,
+) -> Vec<u8> {
+    let types = funcs.iter().map(|(_, ty, _)| ty.clone()).collect();
+    let decls = (0..funcs.len()).map(|idx| leb(idx as u64)).collect();
+    let exports = funcs
+        .iter()
+        .enumerate()
+        .map(|(idx, (name, _, _))| export(name, FUNC, idx as u32))
+        .chain(exports)
+        .collect();
+    let bodies = funcs
+        .into_iter()
+        .map(|(_, _, code)| body(&[], code))
+        .collect();
+
+    let mut sections = vec![
+        section(1, vec(types)),
+        section(3, vec(decls)),
+        section(7, vec(exports)),
+        section(10, vec(bodies)),
+    ];
+    sections.extend(extra);
+    sections.sort_by_key(|section| section[0]);
+    module(sections)
+}
+
+pub fn instantiate(store: &mut Store, bytes: &[u8]) -> Instance {
+    instantiate_with(store, bytes, &Imports::new())
+}
+
+pub fn instantiate_with(store: &mut Store, bytes: &[u8], imports: &Imports) -> Instance {
+    let module = Module::from_bytes(store, bytes).unwrap();
+    Instance::new(store, module, imports).unwrap()
+}
+
+// The function exported as "f".
+pub fn func(store: &mut Store, bytes: &[u8]) -> Function {
+    instantiate(store, bytes).exports.get_function("f").unwrap()
+}
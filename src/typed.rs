@@ -0,0 +1,278 @@
+//! Statically typed handles to exported functions, taking and returning Rust
+//! values instead of slices of [`Value`]s.
+
+use std::marker::PhantomData;
+
+use anyhow::{bail, Result};
+
+use crate::{
+    error::WasmError,
+    instance::{Exports, Function, Value},
+    module::{FuncType, Val},
+    store::Store,
+};
+
+/// A Rust type with a wasm value type.
+pub trait WasmTy: Sized {
+    fn ty() -> Val;
+    fn into_value(self) -> Value;
+    fn from_value(value: Value) -> Option<Self>;
+}
+
+macro_rules! wasm_ty {
+    ($ty:ty, $variant:ident) => {
+        impl WasmTy for $ty {
+            fn ty() -> Val {
+                Val::$variant
+            }
+
+            fn into_value(self) -> Value {
+                Value::$variant(self)
+            }
+
+            fn from_value(value: Value) -> Option<Self> {
+                match value {
+                    Value::$variant(value) => Some(value),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+wasm_ty!(i32, I32);
+wasm_ty!(i64, I64);
+wasm_ty!(f32, F32);
+wasm_ty!(f64, F64);
+
+/// The params of a [`TypedFunc`]: `()`, a single value or a tuple of up to 4
+/// values.
+pub trait WasmParams {
+    fn types() -> Vec<Val>;
+    fn into_values(self) -> Vec<Value>;
+}
+
+/// The results of a [`TypedFunc`]: `()`, a single value or a tuple of up to 4
+/// values.
+pub trait WasmResults: Sized {
+    fn types() -> Vec<Val>;
+    // `None` if the values don't have the expected types.
+    fn from_values(values: Vec<Value>) -> Option<Self>;
+}
+
+impl<T: WasmTy> WasmParams for T {
+    fn types() -> Vec<Val> {
+        vec![T::ty()]
+    }
+
+    fn into_values(self) -> Vec<Value> {
+        vec![self.into_value()]
+    }
+}
+
+impl<T: WasmTy> WasmResults for T {
+    fn types() -> Vec<Val> {
+        vec![T::ty()]
+    }
+
+    fn from_values(values: Vec<Value>) -> Option<Self> {
+        match values[..] {
+            [value] => T::from_value(value),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! wasm_tuple {
+    ($($name:ident),*) => {
+        impl<$($name: WasmTy),*> WasmParams for ($($name,)*) {
+            fn types() -> Vec<Val> {
+                vec![$($name::ty()),*]
+            }
+
+            #[allow(non_snake_case)]
+            fn into_values(self) -> Vec<Value> {
+                let ($($name,)*) = self;
+                vec![$($name.into_value()),*]
+            }
+        }
+
+        impl<$($name: WasmTy),*> WasmResults for ($($name,)*) {
+            fn types() -> Vec<Val> {
+                vec![$($name::ty()),*]
+            }
+
+            #[allow(non_snake_case)]
+            fn from_values(values: Vec<Value>) -> Option<Self> {
+                match values[..] {
+                    [$($name),*] => Some(($($name::from_value($name)?,)*)),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+wasm_tuple!();
+wasm_tuple!(A);
+wasm_tuple!(A, B);
+wasm_tuple!(A, B, C);
+wasm_tuple!(A, B, C, D);
+
+/// The values returned by [`Function::call`], to extract them as Rust values.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Results(Vec<Value>);
+
+impl Results {
+    /// The result at `index`, failing if it isn't a `T`.
+    pub fn get<T: WasmTy>(&self, index: usize) -> Result<T> {
+        let Some(value) = self.0.get(index) else {
+            bail!("no result at index {index}, there are {}", self.0.len());
+        };
+        match T::from_value(*value) {
+            Some(value) => Ok(value),
+            None => bail!("result {index} is {value:?}, expected {:?}", T::ty()),
+        }
+    }
+
+    pub fn get_i32(&self, index: usize) -> Result<i32> {
+        self.get(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// All the results at once, failing if they aren't of the types of `T`.
+    pub fn into_tuple<T: WasmResults>(self) -> Result<T> {
+        match T::from_values(self.0.clone()) {
+            Some(results) => Ok(results),
+            None => bail!("results {:?} don't have types {:?}", self.0, T::types()),
+        }
+    }
+
+    pub fn into_values(self) -> Vec<Value> {
+        self.0
+    }
+}
+
+impl From<Vec<Value>> for Results {
+    fn from(values: Vec<Value>) -> Self {
+        Self(values)
+    }
+}
+
+/// An exported function whose signature was checked against `Params` and
+/// `Results` when it was looked up.
+pub struct TypedFunc<Params, Results> {
+    func: Function,
+    _signature: PhantomData<fn(Params) -> Results>,
+}
+
+impl<Params: WasmParams, Results: WasmResults> TypedFunc<Params, Results> {
+    pub fn call(&self, store: &mut Store, params: Params) -> Result<Results, WasmError> {
+        let results = self.func.call(store, &params.into_values())?;
+        Results::from_values(results).ok_or_else(|| {
+            WasmError::Validation(format!(
+                "function didn't return values of types {:?}",
+                Results::types()
+            ))
+        })
+    }
+
+    /// The untyped function, to call it with a slice of values.
+    pub fn func(&self) -> &Function {
+        &self.func
+    }
+}
+
+impl Exports {
+    /// Looks up the function exported as `name`, failing if its signature
+    /// isn't `Params` to `Results`.
+    pub fn get_typed_function<Params: WasmParams, Results: WasmResults>(
+        &self,
+        name: &str,
+    ) -> Result<TypedFunc<Params, Results>> {
+        let func = self.get_function(name)?;
+        let expected = FuncType::new(Params::types(), Results::types());
+        if *func.ty() != expected {
+            bail!(
+                "function {name} has type {:?}, expected {expected:?}",
+                func.ty()
+            );
+        }
+
+        Ok(TypedFunc {
+            func,
+            _signature: PhantomData,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn results() {
+        let code = vec![0x20, 0, 0x20, 1, 0x6d, 0x20, 0, 0x20, 1, 0x6f, 0x0b];
+        let mut store = Store::default();
+        let divmod = func(
+            &mut store,
+            &single_func(&[I32, I32], &[I32, I32], &[], code),
+        );
+        let results = Results::from(
+            divmod
+                .call(&mut store, &[Value::I32(17), Value::I32(5)])
+                .unwrap(),
+        );
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results.get_i32(1).unwrap(), 2);
+        let e = results.get_i32(2).unwrap_err();
+        assert_eq!(e.to_string(), "no result at index 2, there are 2");
+        let e = results.get::<i64>(0).unwrap_err();
+        assert_eq!(e.to_string(), "result 0 is I32(3), expected I64");
+        let e = results.clone().into_tuple::<(i32,)>().unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "results [I32(3), I32(2)] don't have types [I32]"
+        );
+        assert_eq!(results.into_tuple::<(i32, i32)>().unwrap(), (3, 2));
+    }
+
+    #[test]
+    fn typed_call() {
+        let bytes = funcs_module(
+            vec![(
+                "add",
+                func_type(&[I32, I32], &[I32]),
+                vec![0x20, 0, 0x20, 1, 0x6a, 0x0b],
+            )],
+            vec![],
+            vec![],
+        );
+        let mut store = Store::default();
+        let exports = instantiate(&mut store, &bytes).exports;
+        let add = exports
+            .get_typed_function::<(i32, i32), i32>("add")
+            .unwrap();
+        assert_eq!(add.call(&mut store, (12, 42)).unwrap(), 54);
+
+        // Checked when looking the function up
+        let e = exports
+            .get_typed_function::<(i32, i64), i32>("add")
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            "function add has type FuncType { params: [I32, I32], results: [I32] }, expected FuncType { params: [I32, I64], results: [I32] }"
+        );
+        assert!(exports.get_typed_function::<(i32, i32), ()>("add").is_err());
+    }
+}
@@ -0,0 +1,468 @@
+//! Type checking of function bodies, so that malformed modules are rejected
+//! before any of their code runs.
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::module::{Export, ExportKind, Func, Instr, Module, Val};
+
+impl Module {
+    /// Type checks every function body, decoding the bodies that were left
+    /// to be decoded lazily.
+    pub fn validate(&self) -> Result<()> {
+        self.validate_exports()?;
+        for idx in 0..self.funcs.len() as u32 {
+            let idx = idx + self.num_imported_funcs();
+            let func = self.defined_func(idx).expect("index of a defined function");
+            func.body()
+                .and_then(|body| validate_body(self, func, body))
+                .with_context(|| format!("validate {}", self.describe_func(idx)))?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn validate_exports(&self) -> Result<()> {
+        match self.unknown_exports().next() {
+            Some(export) => bail!(
+                "export {} refers to unknown {:?} {}",
+                export.name,
+                export.kind,
+                export.idx
+            ),
+            None => Ok(()),
+        }
+    }
+
+    // The exports referring to a function, global or memory that doesn't
+    // exist.
+    pub(crate) fn unknown_exports(&self) -> impl Iterator<Item = &Export> {
+        self.exports.iter().filter(|export| match export.kind {
+            ExportKind::Func => self.func_type(export.idx).is_none(),
+            ExportKind::Global => self.global_type(export.idx).is_none(),
+            ExportKind::Memory => export.idx as usize >= self.memories.len(),
+            // Tables aren't parsed
+            ExportKind::Table => false,
+        })
+    }
+}
+
+// Number of bodies validated by the current thread, for tests to check when
+// validation runs.
+#[cfg(test)]
+thread_local! {
+    pub(crate) static VALIDATED_BODIES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+// Follows the types of the operand stack through `body`, checking that every
+// instruction finds operands of the types it expects and that every block,
+// the body included, leaves values of its result types. Returns the maximum
+// height the operand stack reaches.
+pub(crate) fn validate_body(module: &Module, func: &Func, body: &[Instr]) -> Result<usize> {
+    check_body(module, func, body, None)
+}
+
+// A problem found by `validate_body_all`, at instruction `offset` of the
+// body, `None` for the end of the body.
+pub(crate) struct BodyError {
+    pub(crate) offset: Option<usize>,
+    pub(crate) error: anyhow::Error,
+}
+
+// Like `validate_body`, but carries on past problems to return all of them.
+pub(crate) fn validate_body_all(module: &Module, func: &Func, body: &[Instr]) -> Vec<BodyError> {
+    let mut errors = Vec::new();
+    check_body(module, func, body, Some(&mut errors)).expect("errors are collected");
+    errors
+}
+
+// Collects the problems in `errors` when given, recovering from each one
+// by treating the rest of the block as unreachable, instead of returning the
+// first one.
+fn check_body(
+    module: &Module,
+    func: &Func,
+    body: &[Instr],
+    mut errors: Option<&mut Vec<BodyError>>,
+) -> Result<usize> {
+    #[cfg(test)]
+    VALIDATED_BODIES.with(|count| count.set(count.get() + 1));
+    let locals: Vec<_> = func.ty().params.iter().chain(func.locals()).collect();
+    let mut validator = Validator {
+        stack: Vec::new(),
+        max_height: 0,
+        frames: vec![Frame {
+            label_types: func.ty().results.clone(),
+            params: Vec::new(),
+            results: func.ty().results.clone(),
+            height: 0,
+            unreachable: false,
+            is_if: false,
+        }],
+    };
+
+    for (offset, instr) in body.iter().enumerate() {
+        if validator.frames.is_empty() {
+            let error = anyhow!("instruction at {offset} is past the end of the body");
+            match errors.as_deref_mut() {
+                Some(errors) => errors.push(BodyError {
+                    offset: Some(offset),
+                    error,
+                }),
+                None => return Err(error),
+            }
+            break;
+        }
+
+        let depth = validator.frames.len();
+        if let Err(error) = validator.instr(module, &locals, instr) {
+            let Some(errors) = errors.as_deref_mut() else {
+                return Err(error.context(format!("{instr:?} at {offset}")));
+            };
+            errors.push(BodyError {
+                offset: Some(offset),
+                error,
+            });
+            validator.recover(instr, depth);
+        }
+        validator.max_height = validator.max_height.max(validator.stack.len());
+    }
+
+    // The `end` of the body itself can be left out
+    let end = match validator.frames.len() {
+        0 => Ok(()),
+        1 => validator.end_frame().map(|_| ()),
+        open => Err(anyhow!("{} blocks aren't closed by an end", open - 1)),
+    };
+    if let Err(error) = end {
+        match errors {
+            Some(errors) => errors.push(BodyError {
+                offset: None,
+                error,
+            }),
+            None => return Err(error),
+        }
+    }
+
+    Ok(validator.max_height)
+}
+
+// A block being validated, the body of the function being the outermost one.
+struct Frame {
+    // Types of the values a branch to the block takes.
+    label_types: Vec<Val>,
+    params: Vec<Val>,
+    results: Vec<Val>,
+    // Height of the operand stack below the block's params.
+    height: usize,
+    // Whether the rest of the block follows an unconditional branch. The
+    // operands it pops below `height` can then have any type.
+    unreachable: bool,
+    // Whether the block is an `if` whose `else` hasn't been seen yet.
+    is_if: bool,
+}
+
+struct Validator {
+    stack: Vec<Val>,
+    // Highest the stack has been after an instruction.
+    max_height: usize,
+    frames: Vec<Frame>,
+}
+
+impl Validator {
+    fn instr(&mut self, module: &Module, locals: &[&Val], instr: &Instr) -> Result<()> {
+        match instr {
+            Instr::Block(ty) | Instr::Loop(ty) | Instr::If(ty) => {
+                let Some(ty) = module.block_signature(ty) else {
+                    bail!("unknown block type {ty:?}");
+                };
+                if let Instr::If(_) = instr {
+                    self.pop(&Val::I32)?;
+                }
+                self.pop_all(&ty.params)?;
+                let label_types = match instr {
+                    Instr::Loop(_) => ty.params.clone(),
+                    _ => ty.results.clone(),
+                };
+                self.frames.push(Frame {
+                    label_types,
+                    params: ty.params.clone(),
+                    results: ty.results,
+                    height: self.stack.len(),
+                    unreachable: false,
+                    is_if: matches!(instr, Instr::If(_)),
+                });
+                self.stack.extend(ty.params);
+            }
+            Instr::Else => {
+                if !self.frame().is_if {
+                    bail!("else outside of an if");
+                }
+                let frame = self.end_frame()?;
+                self.frames.push(Frame {
+                    unreachable: false,
+                    is_if: false,
+                    ..frame
+                });
+                self.stack.extend(self.frame().params.clone());
+            }
+            Instr::End => {
+                let frame = self.end_frame()?;
+                // Without an else the params are the results when the
+                // condition is false
+                if frame.is_if && frame.params != frame.results {
+                    bail!("if without an else must leave its params unchanged");
+                }
+                self.stack.extend(frame.results);
+            }
+            Instr::Br(depth) => {
+                let types = self.label_types(*depth)?;
+                self.pop_all(&types)?;
+                self.set_unreachable();
+            }
+            Instr::BrIf(depth) => {
+                self.pop(&Val::I32)?;
+                let types = self.label_types(*depth)?;
+                self.pop_all(&types)?;
+                self.stack.extend(types);
+            }
+            Instr::Return => {
+                let results = self.frames[0].results.clone();
+                self.pop_all(&results)?;
+                self.set_unreachable();
+            }
+            Instr::Unreachable => self.set_unreachable(),
+            Instr::LocalGet(n) | Instr::LocalSet(n) | Instr::LocalTee(n) => {
+                let Some(ty) = locals.get(*n as usize) else {
+                    bail!("unknown local {n}");
+                };
+                if !matches!(instr, Instr::LocalGet(_)) {
+                    self.pop(ty)?;
+                }
+                if !matches!(instr, Instr::LocalSet(_)) {
+                    self.stack.push((*ty).clone());
+                }
+            }
+            Instr::GlobalGet(n) | Instr::GlobalSet(n) => {
+                let Some(ty) = module.global_type(*n) else {
+                    bail!("unknown global {n}");
+                };
+                if let Instr::GlobalSet(_) = instr {
+                    if !ty.mutable {
+                        bail!("global {n} is immutable");
+                    }
+                    self.pop(&ty.val)?;
+                } else {
+                    self.stack.push(ty.val.clone());
+                }
+            }
+            Instr::Call(idx) | Instr::ReturnCall(idx) => {
+                let Some(ty) = module.func_type(*idx) else {
+                    bail!("call to unknown function {idx}");
+                };
+                self.pop_all(&ty.params)?;
+                if let Instr::ReturnCall(_) = instr {
+                    if ty.results != self.frames[0].results {
+                        bail!("tail call to a function returning {:?}", ty.results);
+                    }
+                    self.set_unreachable();
+                } else {
+                    self.stack.extend(ty.results.iter().cloned());
+                }
+            }
+            Instr::ReturnCallIndirect(ty, table) => {
+                let Some(ty) = module.types.get(*ty as usize) else {
+                    bail!("unknown type {ty}");
+                };
+                if ty.results != self.frames[0].results {
+                    bail!("tail call to a function returning {:?}", ty.results);
+                }
+                // Tables aren't supported, so there is nothing to call
+                bail!("unknown table {table}");
+            }
+            _ => {
+                let needs_memory = matches!(
+                    instr,
+                    Instr::I32Load(_)
+                        | Instr::I32Load8U(_)
+                        | Instr::I32Store(_)
+                        | Instr::MemorySize
+                        | Instr::MemoryGrow
+                        | Instr::MemoryInit(_)
+                        | Instr::MemoryCopy
+                        | Instr::MemoryFill
+                );
+                if needs_memory && module.memories.is_empty() {
+                    bail!("unknown memory 0");
+                }
+                // Alignments are log2 of the number of bytes
+                let access = match instr {
+                    Instr::I32Load(memarg) | Instr::I32Store(memarg) => Some((memarg, 2)),
+                    Instr::I32Load8U(memarg) => Some((memarg, 0)),
+                    _ => None,
+                };
+                if let Some((memarg, natural)) = access {
+                    if memarg.align > natural {
+                        bail!("alignment must not be larger than {} bytes", 1 << natural);
+                    }
+                }
+                if let Instr::MemoryInit(idx) | Instr::DataDrop(idx) = instr {
+                    if *idx as usize >= module.data.len() {
+                        bail!("unknown data segment {idx}");
+                    }
+                }
+                self.pop_all(instr.operand_types())?;
+                self.stack.extend(instr.result_types().iter().cloned());
+            }
+        }
+
+        Ok(())
+    }
+
+    // The innermost block.
+    fn frame(&self) -> &Frame {
+        self.frames.last().expect("only called inside of the body")
+    }
+
+    fn pop(&mut self, expected: &Val) -> Result<()> {
+        let frame = self.frame();
+        if self.stack.len() == frame.height {
+            if frame.unreachable {
+                return Ok(());
+            }
+            bail!("expected an operand of type {expected:?} but the stack is empty");
+        }
+
+        let actual = self.stack.pop().expect("the stack is above the block");
+        if actual != *expected {
+            bail!("expected an operand of type {expected:?}, got {actual:?}");
+        }
+        Ok(())
+    }
+
+    // Pops operands of types `types`, the last one is on top of the stack.
+    fn pop_all(&mut self, types: &[Val]) -> Result<()> {
+        for ty in types.iter().rev() {
+            self.pop(ty)?;
+        }
+        Ok(())
+    }
+
+    fn set_unreachable(&mut self) {
+        let frame = self
+            .frames
+            .last_mut()
+            .expect("only called inside of the body");
+        self.stack.truncate(frame.height);
+        frame.unreachable = true;
+    }
+
+    fn label_types(&self, depth: u32) -> Result<Vec<Val>> {
+        match self.frames.len().checked_sub(depth as usize + 1) {
+            Some(idx) => Ok(self.frames[idx].label_types.clone()),
+            None => bail!("branch to unknown label {depth}"),
+        }
+    }
+
+    // Checks that the innermost block leaves exactly its results on the
+    // stack, and pops it. The block is left open on error.
+    fn end_frame(&mut self) -> Result<Frame> {
+        let results = self.frame().results.clone();
+        self.pop_all(&results)?;
+        let height = self.frame().height;
+        if self.stack.len() != height {
+            bail!(
+                "{} values are left on the stack at the end of the block",
+                self.stack.len() - height
+            );
+        }
+        Ok(self.frames.pop().expect("only called inside of the body"))
+    }
+
+    // Gets back to a consistent state after `instr` failed to validate,
+    // `depth` being the number of open blocks before it.
+    fn recover(&mut self, instr: &Instr, depth: usize) {
+        match instr {
+            // The block is opened anyway, so that its end has one to close
+            Instr::Block(_) | Instr::Loop(_) | Instr::If(_) if self.frames.len() == depth => {
+                self.frames.push(Frame {
+                    label_types: Vec::new(),
+                    params: Vec::new(),
+                    results: Vec::new(),
+                    height: self.stack.len(),
+                    unreachable: true,
+                    is_if: matches!(instr, Instr::If(_)),
+                });
+            }
+            // Likewise the block is closed anyway
+            Instr::End if self.frames.len() == depth => {
+                let frame = self.frames.pop().expect("only called inside of the body");
+                self.stack.truncate(frame.height);
+                self.stack.extend(frame.results);
+            }
+            _ if !self.frames.is_empty() => self.set_unreachable(),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, imports::Imports, instance::Instance, store::Store, test_util::*};
+
+    fn validate(params: &[u8], results: &[u8], code: Vec<u8>) -> String {
+        let bytes = single_func(params, results, &[], code);
+        let module = Module::from_bytes(&Store::default(), &bytes).unwrap();
+        format!("{:#}", module.validate().unwrap_err())
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let e = validate(&[], &[I32], vec![0x41, 1, 0x6a, 0x0b]);
+        assert!(
+            e.contains("expected an operand of type I32 but the stack is empty"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn local_out_of_range() {
+        let e = validate(&[I32], &[I32], vec![0x20, 1, 0x0b]);
+        assert!(e.contains("unknown local 1"), "{e}");
+    }
+
+    #[test]
+    fn lazy_bodies_are_validated_when_looked_up() {
+        // global.set on an immutable global
+        let bytes = module(vec![
+            section(1, vec(vec![func_type(&[], &[])])),
+            section(3, vec(vec![leb(0)])),
+            section(6, vec(vec![vec![I32, 0, 0x41, 0, 0x0b]])),
+            section(7, vec(vec![export("f", FUNC, 0)])),
+            section(10, vec(vec![body(&[], vec![0x41, 9, 0x24, 0, 0x0b])])),
+        ]);
+        let mut store = Store::new(Config {
+            lazy_function_bodies: true,
+            ..Default::default()
+        });
+        let instance = instantiate(&mut store, &bytes);
+        let e = instance.exports.get_function("f").err().unwrap();
+        assert!(format!("{e:#}").contains("global 0 is immutable"), "{e:#}");
+    }
+
+    #[test]
+    fn lazy_bodies_have_their_call_memory_checked() {
+        let code = vec![0x41, 1, 0x41, 2, 0x41, 3, 0x6a, 0x6a, 0x0b];
+        let bytes = single_func(&[], &[I32], &[], code);
+        let mut store = Store::new(Config {
+            lazy_function_bodies: true,
+            max_call_memory_slots: Some(2),
+            ..Default::default()
+        });
+        let module = Module::from_bytes(&store, &bytes).unwrap();
+        let instance = Instance::new(&mut store, module, &Imports::new()).unwrap();
+        let f = instance.exports.get_function("f").unwrap();
+        let e = f.call(&mut store, &[]).unwrap_err();
+        assert!(e.to_string().contains("call needs 3 stack slots"), "{e}");
+    }
+}